@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::Local;
+use sha1::{Digest, Sha1};
+
+use crate::dir_csv::hash_file;
+use crate::error::{DirdiffError, Result};
+use crate::names::decode_os_str;
+use crate::plan::{Operation, Plan};
+
+// Deleted files are moved under here, in a directory per run. Hidden, so `record` skips it.
+pub const TRASH_DIR: &str = ".dirdiff-trash";
+
+pub fn load_plan(path: &Path) -> Result<Plan> {
+    let file = File::open(path).map_err(|e| DirdiffError::io(path, e))?;
+    serde_json::from_reader(io::BufReader::new(file))
+        .map_err(|e| DirdiffError::parse(path, Some(e.line() as u64), &e.to_string()))
+}
+
+// A path of the plan under `root`. Plans are plain files, so paths that would lead out of the
+// root are refused.
+fn plan_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = PathBuf::from(decode_os_str(path));
+
+    if relative.as_os_str().is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(DirdiffError::invalid_path(&relative, "plan paths must be relative and stay inside the directory"));
+    }
+
+    Ok(root.join(relative))
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+// Whether `path` already has the planned contents, so the operation was done by an earlier run.
+// Anything else in its place is in the way.
+fn already_done(path: &Path, hash: &str) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Err(_) => return Ok(false),
+        Ok(metadata) if metadata.is_dir() => return Err(DirdiffError::PathInTheWay(path.to_path_buf())),
+        Ok(_) => ()
+    }
+
+    match hash_file(path) {
+        Ok((found, _)) if found == hash => Ok(true),
+        Ok(_) => Err(DirdiffError::PathInTheWay(path.to_path_buf())),
+        Err(error) => Err(DirdiffError::io(path, error))
+    }
+}
+
+// Hashes whatever goes through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha1
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Stream `from` into a hidden partial file next to `to`, and only put it in place once its hash
+// is the planned one. An interrupted copy leaves nothing at `to`.
+fn copy_verified(from: &Path, to: &Path, hash: &str) -> Result<()> {
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let partial = to.with_file_name(format!(".{}.dirdiff-part", name));
+
+    let mut input = File::open(from).map_err(|e| DirdiffError::io(from, e))?;
+    let output = File::create(&partial).map_err(|e| DirdiffError::io(&partial, e))?;
+    let mut writer = HashingWriter { inner: output, hasher: Sha1::new() };
+    io::copy(&mut input, &mut writer).map_err(|e| DirdiffError::io(&partial, e))?;
+    writer.inner.sync_all().map_err(|e| DirdiffError::io(&partial, e))?;
+
+    let found = hex::encode(writer.hasher.finalize());
+    if found != hash {
+        let _ = fs::remove_file(&partial);
+        return Err(DirdiffError::HashMismatch { path: from.to_path_buf(), expected: hash.to_string(), found });
+    }
+
+    fs::rename(&partial, to).map_err(|e| DirdiffError::io(to, e))
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to).map_err(|e| DirdiffError::io(from, e))
+}
+
+fn has_hash(path: &Path, hash: &str) -> Result<bool> {
+    hash_file(path).map(|(found, _)| found == hash).map_err(|e| DirdiffError::io(path, e))
+}
+
+// Carry out one operation, returning false when an earlier run already did. `destinations` are
+// the paths operations of the plan put files at.
+fn apply_operation(operation: &Operation, source: &Path, target: &Path, trash: Option<&Path>,
+                   destinations: &HashSet<&str>) -> Result<bool> {
+    match operation {
+        Operation::Mkdir { path } => {
+            let dir = plan_path(target, path)?;
+            if dir.is_dir() {
+                return Ok(false);
+            }
+            fs::create_dir_all(&dir).map_err(|e| DirdiffError::io(&dir, e))?;
+        }
+        Operation::Move { from, to, hash } | Operation::Rename { from, to, hash } => {
+            let to = plan_path(target, to)?;
+            if already_done(&to, hash)? {
+                return Ok(false);
+            }
+
+            // When moves go round in a cycle, an earlier run may have put another file where this
+            // one was, so only its contents tell whether it is still to be moved
+            let from_path = plan_path(target, from)?;
+            if destinations.contains(from.as_str()) && exists(&from_path) && !has_hash(&from_path, hash)? {
+                return Ok(false);
+            }
+            rename(&from_path, &to)?;
+        }
+        Operation::CopyLocalDuplicate { from, to, hash, .. } => {
+            let to = plan_path(target, to)?;
+            if already_done(&to, hash)? {
+                return Ok(false);
+            }
+            copy_verified(&plan_path(target, from)?, &to, hash)?;
+        }
+        Operation::Transfer { path, hash, .. } => {
+            let to = plan_path(target, path)?;
+            if already_done(&to, hash)? {
+                return Ok(false);
+            }
+            copy_verified(&plan_path(source, path)?, &to, hash)?;
+        }
+        Operation::Delete { path, hash } => {
+            // Never delete contents the plan did not know about. Those may also be a file or
+            // directory an earlier run put in place of the deleted file.
+            let file = plan_path(target, path)?;
+            if !fs::symlink_metadata(&file).map(|m| m.is_file()).unwrap_or(false) || !has_hash(&file, hash)? {
+                return Ok(false);
+            }
+
+            match trash {
+                Some(trash) => {
+                    let trashed = plan_path(trash, path)?;
+                    if let Some(parent) = trashed.parent() {
+                        fs::create_dir_all(parent).map_err(|e| DirdiffError::io(parent, e))?;
+                    }
+                    rename(&file, &trashed)?;
+                }
+                None => fs::remove_file(&file).map_err(|e| DirdiffError::io(&file, e))?
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+// The `apply` command: carry out a plan against its target directory, stopping at the first
+// failure. Operations an earlier, interrupted run already did are skipped, so running it again
+// resumes where it stopped.
+pub fn apply_plan(out: &mut dyn Write, plan: &Plan, use_trash: bool, dry_run: bool, verbose: bool) -> Result<()> {
+    let write_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+    let source = PathBuf::from(decode_os_str(&plan.source));
+    let target = PathBuf::from(decode_os_str(&plan.target));
+
+    if !target.is_dir() {
+        return Err(DirdiffError::NotADirectory(target));
+    }
+
+    if dry_run {
+        for operation in &plan.operations {
+            writeln!(out, "Would {}", operation.describe()).map_err(write_error)?;
+        }
+        return Ok(());
+    }
+
+    let trash = target.join(TRASH_DIR).join(Local::now().format("%Y%m%d-%H%M%S").to_string());
+    let trash = if use_trash { Some(trash.as_path()) } else { None };
+    let destinations = plan.operations.iter()
+        .filter_map(|operation| match operation {
+            Operation::Move { to, .. } | Operation::Rename { to, .. } | Operation::CopyLocalDuplicate { to, .. } =>
+                Some(to.as_str()),
+            Operation::Transfer { path, .. } => Some(path.as_str()),
+            _ => None
+        })
+        .collect::<HashSet<&str>>();
+    let (mut applied, mut skipped) = (0, 0);
+
+    for operation in &plan.operations {
+        if apply_operation(operation, &source, &target, trash, &destinations)? {
+            applied += 1;
+            writeln!(out, "{}", operation.describe()).map_err(write_error)?;
+        } else {
+            skipped += 1;
+            if verbose { writeln!(out, "Already done: {}", operation.describe()).map_err(write_error)?; }
+        }
+    }
+
+    writeln!(out, "Applied {} operations, {} were already done; record {} again to update its manifest",
+             applied, skipped, target.display()).map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    fn scratch_dir(test: &str) -> ScratchDir {
+        let dir = ScratchDir::new(&format!("apply-{}", test));
+        fs::create_dir(dir.join("source")).unwrap();
+        fs::create_dir(dir.join("target")).unwrap();
+        dir
+    }
+
+    fn hash(contents: &str) -> String {
+        hex::encode(Sha1::digest(contents.as_bytes()))
+    }
+
+    fn write(dir: &Path, path: &str, contents: &str) {
+        fs::write(dir.join(path), contents).unwrap();
+    }
+
+    fn read(dir: &Path, path: &str) -> String {
+        fs::read_to_string(dir.join(path)).unwrap()
+    }
+
+    fn apply(plan: &Plan, use_trash: bool) -> Result<String> {
+        let mut out = Vec::new();
+        apply_plan(&mut out, plan, use_trash, false, false)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    // Swaps `a` and `b` through a parked file, transfers `sub/new`, copies `b` and deletes `old`
+    fn swap_plan(dir: &Path) -> Plan {
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        write(&target, "a", "A");
+        write(&target, "b", "B");
+        write(&target, "old", "O");
+        fs::create_dir(source.join("sub")).unwrap();
+        write(&source, "sub/new", "N");
+
+        let rename = |from: &str, to: &str, contents: &str| Operation::Rename {
+            from: from.to_string(), to: to.to_string(), hash: hash(contents)
+        };
+
+        Plan {
+            source: source.to_string_lossy().into_owned(),
+            target: target.to_string_lossy().into_owned(),
+            operations: vec![
+                Operation::Mkdir { path: "sub".to_string() },
+                rename("a", "a.dirdiff-tmp", "A"),
+                rename("b", "a", "B"),
+                rename("a.dirdiff-tmp", "b", "A"),
+                Operation::Transfer { path: "sub/new".to_string(), hash: hash("N"), size: Some(1) },
+                Operation::CopyLocalDuplicate { from: "b".to_string(), to: "sub/copy".to_string(), hash: hash("A"),
+                                                size: Some(1) },
+                Operation::Delete { path: "old".to_string(), hash: hash("O") }
+            ]
+        }
+    }
+
+    fn assert_applied(target: &Path) {
+        assert_eq!(read(target, "a"), "B");
+        assert_eq!(read(target, "b"), "A");
+        assert_eq!(read(target, "sub/new"), "N");
+        assert_eq!(read(target, "sub/copy"), "A");
+        assert!(!exists(&target.join("old")));
+        assert!(!exists(&target.join("a.dirdiff-tmp")));
+    }
+
+    #[test]
+    fn plan_paths_stay_inside_the_root() {
+        let root = Path::new("/target");
+
+        assert_eq!(plan_path(root, "dir/a").unwrap(), Path::new("/target/dir/a"));
+        for path in &["", "..", "../a", "dir/../../a", "./a", "/etc/passwd", "%%..%2Fa"] {
+            assert!(plan_path(root, path).is_err(), "{} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn escaping_paths_are_refused_before_touching_anything() {
+        let dir = scratch_dir("escape");
+        write(&dir, "outside", "X");
+        let mut plan = swap_plan(&dir);
+        plan.operations.insert(0, Operation::Delete { path: "../outside".to_string(), hash: hash("X") });
+
+        assert!(matches!(apply(&plan, false), Err(DirdiffError::InvalidPath { .. })));
+        assert_eq!(read(&dir, "outside"), "X");
+        assert_eq!(read(&dir.join("target"), "a"), "A");
+    }
+
+    #[test]
+    fn applying_again_finds_everything_done() {
+        let dir = scratch_dir("again");
+        let target = dir.join("target");
+        let plan = swap_plan(&dir);
+
+        assert!(apply(&plan, true).unwrap().starts_with("mkdir"));
+        assert_applied(&target);
+        let trash = fs::read_dir(target.join(TRASH_DIR)).unwrap().next().unwrap().unwrap().path();
+        assert_eq!(read(&trash, "old"), "O");
+
+        let output = apply(&plan, true).unwrap();
+        assert!(output.starts_with("Applied 0 operations, 7 were already done"), "{}", output);
+        assert_applied(&target);
+    }
+
+    #[test]
+    fn interrupted_runs_resume_in_the_middle_of_a_cycle() {
+        let dir = scratch_dir("resume");
+        let target = dir.join("target");
+        let mut plan = swap_plan(&dir);
+
+        // Stop right after `b` took the place of the parked `a`
+        let rest = plan.operations.split_off(3);
+        apply(&plan, false).unwrap();
+        assert_eq!(read(&target, "a"), "B");
+        assert_eq!(read(&target, "a.dirdiff-tmp"), "A");
+
+        plan.operations.extend(rest);
+        let output = apply(&plan, false).unwrap();
+        assert!(output.ends_with(&format!("Applied 4 operations, 3 were already done; record {} again to update its manifest\n",
+                                          target.display())), "{}", output);
+        assert_applied(&target);
+    }
+
+    #[test]
+    fn changed_files_are_not_deleted() {
+        let dir = scratch_dir("changed");
+        let target = dir.join("target");
+        let plan = swap_plan(&dir);
+        write(&target, "old", "changed since the plan");
+
+        apply(&plan, false).unwrap();
+        assert_eq!(read(&target, "old"), "changed since the plan");
+    }
+
+    #[test]
+    fn transfers_with_other_contents_are_not_put_in_place() {
+        let dir = scratch_dir("mismatch");
+        let target = dir.join("target");
+        let plan = swap_plan(&dir);
+        write(&dir.join("source"), "sub/new", "changed since the plan");
+
+        match apply(&plan, false) {
+            Err(DirdiffError::HashMismatch { .. }) => (),
+            _ => panic!("changed transfer applied")
+        }
+        assert!(!exists(&target.join("sub/new")));
+        assert!(!exists(&target.join("sub/.new.dirdiff-part")));
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::error::{DirdiffError, Result};
+
+// Lock file guarding the manifests of a directory. The manifests themselves are replaced on
+// every write, so a lock held on them would not outlive the first rename.
+pub const LOCK_NAME: &str = ".dirdiff.lock";
+
+// Exclusive advisory lock on a directory's manifests, released when dropped
+pub struct ManifestLock {
+    file: File
+}
+
+impl Drop for ManifestLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock anyway, so a failure here is harmless
+        let _ = self.file.unlock();
+    }
+}
+
+// Wait until no other dirdiff process is writing the manifests of `dir`
+pub fn lock_manifest(dir: &Path, verbose: bool) -> Result<ManifestLock> {
+    let path = dir.join(LOCK_NAME);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| DirdiffError::io(&path, e))?;
+
+    if file.try_lock_exclusive().is_err() {
+        if verbose { println!("Waiting for the lock on {}", &path.display()); }
+        file.lock_exclusive().map_err(|e| DirdiffError::io(&path, e))?;
+    }
+
+    Ok(ManifestLock { file })
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Durably replace `path` with the fully written `temp_path`. Until the rename, readers and
+// crashes only ever see the previous contents.
+pub fn commit_temp_file(file: File, temp_path: &Path, path: &Path) -> Result<()> {
+    file.sync_all().map_err(|e| DirdiffError::io(temp_path, e))?;
+    drop(file);
+
+    fs::rename(temp_path, path).map_err(|e| DirdiffError::io(path, e))?;
+
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    sync_dir(dir).map_err(|e| DirdiffError::io(dir, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    #[test]
+    fn committed_temp_files_replace_the_target() {
+        let dir = ScratchDir::new("atomic-commit");
+        let (path, temp_path) = (dir.join("manifest"), dir.join("manifest.tmp"));
+        fs::write(&path, "old").unwrap();
+        fs::write(&temp_path, "new").unwrap();
+
+        // Until the commit the target keeps its previous contents
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+        commit_temp_file(File::open(&temp_path).unwrap(), &temp_path, &path).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!temp_path.exists());
+    }
+
+    #[test]
+    fn manifest_lock_is_exclusive_until_dropped() {
+        let dir = ScratchDir::new("atomic-lock");
+        let lock = lock_manifest(&dir, false).unwrap();
+
+        let other = File::open(dir.join(LOCK_NAME)).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+    }
+}
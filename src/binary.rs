@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::dir_csv::millis;
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+
+// Binary manifest layout, everything after the header being zstd-compressed:
+//
+//   magic, format version
+//   one or more segments, each in its own zstd frame:
+//     directory table additions: count, then each directory path
+//     revision count, then per revision in chronological order:
+//       revision date in millis
+//       indices of the previous revision's entries that are gone, ascending and delta-encoded
+//       entries that are new in this revision
+//
+// Recording a revision appends a segment with its delta, so the history is not compressed
+// again. Directory indices and deltas carry on across segments.
+//
+// Each revision's entries are kept sorted by directory index, then name and the remaining fields,
+// so a revision is rebuilt by dropping the removed entries from the previous one and merging in
+// the added ones.
+pub const BINARY_MAGIC: &[u8; 4] = b"DDBM";
+// Version 2 added file modification times
+const BINARY_VERSION: u8 = 2;
+const COMPRESSION_LEVEL: i32 = 19;
+
+const HASH_TEXT: u8 = 0;
+const HASH_SHA1: u8 = 1;
+
+// An entry stripped of its revision date, with its directory interned
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Entry {
+    path: u64,
+    name: String,
+    hash: String,
+    size: Option<u64>,
+    error: Option<String>,
+    mtime: Option<u64>
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Optional values are stored shifted by one, zero meaning absent
+fn write_optional_bytes(out: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            write_varint(out, bytes.len() as u64 + 1);
+            out.extend_from_slice(bytes);
+        }
+        None => write_varint(out, 0)
+    }
+}
+
+// SHA-1 hex digests are stored as raw bytes, anything else verbatim
+fn write_hash(out: &mut Vec<u8>, hash: &str) {
+    match hex::decode(hash) {
+        Ok(bytes) if bytes.len() == 20 && hex::encode(&bytes) == hash => {
+            out.push(HASH_SHA1);
+            out.extend_from_slice(&bytes);
+        }
+        _ => {
+            out.push(HASH_TEXT);
+            write_bytes(out, hash.as_bytes());
+        }
+    }
+}
+
+fn write_entry(out: &mut Vec<u8>, entry: &Entry) {
+    write_varint(out, entry.path);
+    write_bytes(out, entry.name.as_bytes());
+    write_hash(out, &entry.hash);
+    write_varint(out, entry.size.map(|s| s + 1).unwrap_or(0));
+    write_optional_bytes(out, entry.error.as_ref().map(|e| e.as_bytes()));
+    write_varint(out, entry.mtime.map(|m| m + 1).unwrap_or(0));
+}
+
+// Indices of `previous` entries missing from `current`, and `current` entries new to it. Both
+// lists are sorted, and are walked together so duplicate entries are accounted for.
+fn sorted_delta<'a>(previous: &[Entry], current: &'a [Entry]) -> (Vec<u64>, Vec<&'a Entry>) {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < previous.len() || j < current.len() {
+        match (previous.get(i), current.get(j)) {
+            (Some(prev), Some(cur)) if prev == cur => {
+                i += 1;
+                j += 1;
+            }
+            (Some(prev), Some(cur)) if prev < cur => {
+                removed.push(i as u64);
+                i += 1;
+            }
+            (Some(_), None) => {
+                removed.push(i as u64);
+                i += 1;
+            }
+            (_, Some(cur)) => {
+                added.push(cur);
+                j += 1;
+            }
+            (None, None) => break
+        }
+    }
+
+    (removed, added)
+}
+
+// Directories interned so far and the entries of the last revision, which the next revision is
+// delta-encoded against
+#[derive(Default)]
+pub struct BinaryState {
+    version: u8,
+    path_ids: HashMap<String, u64>,
+    last_date: Option<u64>,
+    previous: Vec<Entry>
+}
+
+impl BinaryState {
+    // Whether `docs` can be appended as a segment. Manifests in another version and revisions out
+    // of order need the whole manifest to be rewritten.
+    pub fn can_append(&self, docs: &[Doc]) -> bool {
+        self.version == BINARY_VERSION && docs.iter().all(|d| Some(millis(d.mod_date)) > self.last_date)
+    }
+
+    // Encode the revisions of `docs` as one segment, in its own zstd frame
+    pub fn write_segment(&mut self, out: &mut dyn Write, docs: Vec<Doc>) -> io::Result<()> {
+        let mut new_paths: Vec<String> = Vec::new();
+        let mut revisions: BTreeMap<u64, Vec<Entry>> = BTreeMap::new();
+
+        for doc in docs {
+            let next_id = self.path_ids.len() as u64;
+            let path = *self.path_ids.entry(doc.path.clone()).or_insert_with(|| {
+                new_paths.push(doc.path.clone());
+                next_id
+            });
+
+            revisions.entry(millis(doc.mod_date)).or_default().push(Entry {
+                path,
+                name: doc.name,
+                hash: doc.hash,
+                size: doc.size,
+                error: doc.error,
+                mtime: doc.mtime.map(millis)
+            });
+        }
+
+        let mut payload = Vec::new();
+
+        write_varint(&mut payload, new_paths.len() as u64);
+        for path in &new_paths {
+            write_bytes(&mut payload, path.as_bytes());
+        }
+
+        write_varint(&mut payload, revisions.len() as u64);
+
+        for (date, mut entries) in revisions {
+            entries.sort();
+
+            let (removed, added) = sorted_delta(&self.previous, &entries);
+
+            write_varint(&mut payload, date);
+
+            write_varint(&mut payload, removed.len() as u64);
+            let mut last = 0;
+            for index in removed {
+                write_varint(&mut payload, index - last);
+                last = index;
+            }
+
+            write_varint(&mut payload, added.len() as u64);
+            for entry in added {
+                write_entry(&mut payload, entry);
+            }
+
+            self.last_date = Some(date);
+            self.previous = entries;
+        }
+
+        // The frame checksum lets readers tell a corrupted manifest from a valid one
+        let mut encoder = zstd::stream::write::Encoder::new(out, COMPRESSION_LEVEL)?;
+        encoder.include_checksum(true)?;
+        encoder.write_all(&payload)?;
+        encoder.finish()?;
+
+        Ok(())
+    }
+}
+
+pub fn write_binary_entries(out: &mut dyn Write, docs: Vec<Doc>) -> io::Result<()> {
+    out.write_all(BINARY_MAGIC)?;
+    out.write_all(&[BINARY_VERSION])?;
+
+    BinaryState { version: BINARY_VERSION, ..BinaryState::default() }.write_segment(out, docs)
+}
+
+// Cursor over the decompressed payload, turning truncation into parse errors
+struct Decoder<'a> {
+    path: &'a Path,
+    version: u8,
+    data: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, message: &str) -> DirdiffError {
+        DirdiffError::parse(self.path, None, &format!("{} at byte {}", message, self.offset))
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.offset).ok_or_else(|| self.error("unexpected end of manifest"))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift > 63 {
+                return Err(self.error("varint too long"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.offset.checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| self.error("unexpected end of manifest"))?;
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn string_of_len(&mut self, len: usize) -> Result<String> {
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.error("invalid UTF-8 string"))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.varint()? as usize;
+        self.string_of_len(len)
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>> {
+        match self.varint()? {
+            0 => Ok(None),
+            len => Ok(Some(self.string_of_len(len as usize - 1)?))
+        }
+    }
+
+    fn hash(&mut self) -> Result<String> {
+        match self.byte()? {
+            HASH_SHA1 => Ok(hex::encode(self.bytes(20)?)),
+            HASH_TEXT => self.string(),
+            _ => Err(self.error("unknown hash kind"))
+        }
+    }
+
+    fn entry(&mut self, path_count: u64) -> Result<Entry> {
+        let path = self.varint()?;
+        if path >= path_count {
+            return Err(self.error("directory index out of range"));
+        }
+
+        Ok(Entry {
+            path,
+            name: self.string()?,
+            hash: self.hash()?,
+            size: match self.varint()? {
+                0 => None,
+                size => Some(size - 1)
+            },
+            error: self.optional_string()?,
+            mtime: if self.version >= 2 {
+                match self.varint()? {
+                    0 => None,
+                    mtime => Some(mtime - 1)
+                }
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Decode every revision of a binary manifest, in chronological order, along with what is needed
+// to append further revisions to it
+pub fn read_binary_manifest(path: &Path, input: &mut dyn Read) -> Result<(Vec<Doc>, BinaryState)> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header).map_err(|e| DirdiffError::io(path, e))?;
+
+    if &header[..4] != BINARY_MAGIC {
+        return Err(DirdiffError::parse(path, None, "not a binary dirdiff manifest"));
+    }
+
+    if header[4] > BINARY_VERSION {
+        return Err(DirdiffError::UnsupportedManifestVersion { path: path.to_path_buf(), version: header[4] as u32 });
+    }
+
+    // Appended segments are separate frames, which are decoded one after the other
+    let mut payload = Vec::new();
+    zstd::stream::copy_decode(input, &mut payload).map_err(|e| DirdiffError::io(path, e))?;
+
+    let mut decoder = Decoder { path, version: header[4], data: &payload, offset: 0 };
+    let mut state = BinaryState { version: header[4], ..BinaryState::default() };
+    let mut paths = Vec::new();
+    let mut docs = Vec::new();
+
+    while decoder.offset < payload.len() {
+        let path_count = decoder.varint()?;
+        for _ in 0..path_count {
+            let dir = decoder.string()?;
+            state.path_ids.insert(dir.clone(), paths.len() as u64);
+            paths.push(dir);
+        }
+
+        let revision_count = decoder.varint()?;
+
+        for _ in 0..revision_count {
+            let date = decoder.varint()?;
+
+            let removed_count = decoder.varint()?;
+            let mut removed = vec![false; state.previous.len()];
+            let mut index = 0;
+            for _ in 0..removed_count {
+                index += decoder.varint()?;
+                *removed.get_mut(index as usize).ok_or_else(|| decoder.error("removed entry out of range"))? = true;
+            }
+
+            let mut entries = std::mem::take(&mut state.previous).into_iter()
+                .zip(removed)
+                .filter(|(_, removed)| !removed)
+                .map(|(e, _)| e)
+                .collect::<Vec<Entry>>();
+
+            let added_count = decoder.varint()?;
+            for _ in 0..added_count {
+                entries.push(decoder.entry(paths.len() as u64)?);
+            }
+            entries.sort();
+
+            let mod_date = UNIX_EPOCH + Duration::from_millis(date);
+            for entry in &entries {
+                docs.push(Doc {
+                    hash: entry.hash.clone(),
+                    name: entry.name.clone(),
+                    path: paths[entry.path as usize].clone(),
+                    mod_date,
+                    size: entry.size,
+                    error: entry.error.clone(),
+                    mtime: entry.mtime.map(|m| UNIX_EPOCH + Duration::from_millis(m))
+                });
+            }
+
+            state.last_date = Some(date);
+            state.previous = entries;
+        }
+    }
+
+    Ok((docs, state))
+}
+
+pub fn read_binary_entries(path: &Path, input: &mut dyn Read) -> Result<Vec<Doc>> {
+    Ok(read_binary_manifest(path, input)?.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::doc;
+
+    fn modified(mut doc: Doc, mtime: u64) -> Doc {
+        doc.mtime = Some(UNIX_EPOCH + Duration::from_millis(mtime));
+        doc
+    }
+
+    // Path, name, hash, revision date, size, error and modification time
+    type Fields = (String, String, String, u64, Option<u64>, Option<String>, Option<u64>);
+
+    fn fields(docs: &[Doc]) -> Vec<Fields> {
+        docs.iter()
+            .map(|d| (d.path.clone(), d.name.clone(), d.hash.clone(), millis(d.mod_date), d.size, d.error.clone(),
+                      d.mtime.map(millis)))
+            .collect()
+    }
+
+    fn read(bytes: &[u8]) -> Result<(Vec<Doc>, BinaryState)> {
+        read_binary_manifest(Path::new("test.bin"), &mut &bytes[..])
+    }
+
+    // A manifest of the given version holding a single payload, as version 1 wrote them
+    fn manifest(version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = BINARY_MAGIC.to_vec();
+        bytes.push(version);
+        bytes.extend(zstd::encode_all(payload, 0).unwrap());
+        bytes
+    }
+
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    #[test]
+    fn varints_round_trip() {
+        for value in &[0, 1, 0x7f, 0x80, 300, 1 << 35, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, *value);
+            let mut decoder = Decoder { path: Path::new("test.bin"), version: BINARY_VERSION, data: &out, offset: 0 };
+            assert_eq!(decoder.varint().unwrap(), *value);
+            assert_eq!(decoder.offset, out.len());
+        }
+    }
+
+    #[test]
+    fn truncated_varint_is_an_error() {
+        let mut decoder = Decoder { path: Path::new("test.bin"), version: BINARY_VERSION, data: &[0x80, 0x80], offset: 0 };
+        assert!(decoder.varint().is_err());
+    }
+
+    #[test]
+    fn sorted_delta_accounts_for_duplicates() {
+        let entry = |name: &str| Entry { path: 0, name: name.to_string(), hash: String::new(), size: None, error: None,
+                                          mtime: None };
+        let previous = vec![entry("a"), entry("b"), entry("b"), entry("d")];
+        let current = vec![entry("b"), entry("c"), entry("d"), entry("d")];
+
+        let (removed, added) = sorted_delta(&previous, &current);
+
+        assert_eq!(removed, vec![0, 2]);
+        assert_eq!(added.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn revisions_round_trip() {
+        let mut unreadable = doc("dir/sub", "locked", "", 0, 1000);
+        unreadable.size = None;
+        unreadable.error = Some("Permission denied".to_string());
+        let docs = vec![
+            modified(doc("dir", "a", SHA1, 1, 1000), 10),
+            doc("dir", "b", "not a sha1", 1, 1000),
+            unreadable,
+            modified(doc("dir", "a", SHA1, 1, 2000), 10),
+            doc("dir/new", "b", "not a sha1", 1, 2000),
+        ];
+
+        let mut bytes = Vec::new();
+        write_binary_entries(&mut bytes, docs.clone()).unwrap();
+        let (read_docs, state) = read(&bytes).unwrap();
+
+        let actual = fields(&read_docs);
+        assert_eq!(actual.len(), docs.len());
+        for entry in &fields(&docs) {
+            assert!(actual.contains(entry), "missing {:?}", entry);
+        }
+        assert_eq!(state.last_date, Some(2000));
+    }
+
+    #[test]
+    fn reads_version_1_without_mtimes() {
+        let mut payload = Vec::new();
+        write_varint(&mut payload, 1);
+        write_bytes(&mut payload, b"dir");
+        write_varint(&mut payload, 1);
+        write_varint(&mut payload, 1500);
+        write_varint(&mut payload, 0);
+        write_varint(&mut payload, 1);
+        write_varint(&mut payload, 0);
+        write_bytes(&mut payload, b"a");
+        write_hash(&mut payload, SHA1);
+        write_varint(&mut payload, 5);
+        write_optional_bytes(&mut payload, None);
+
+        let (docs, state) = read(&manifest(1, &payload)).unwrap();
+
+        assert_eq!(fields(&docs), vec![("dir".to_string(), "a".to_string(), SHA1.to_string(), 1500, Some(4), None, None)]);
+        assert!(!state.can_append(&[doc("dir", "a", SHA1, 4, 2000)]));
+    }
+
+    #[test]
+    fn reads_version_2_with_mtimes() {
+        let mut payload = Vec::new();
+        write_varint(&mut payload, 1);
+        write_bytes(&mut payload, b"dir");
+        write_varint(&mut payload, 2);
+        write_varint(&mut payload, 1500);
+        write_varint(&mut payload, 0);
+        write_varint(&mut payload, 1);
+        write_entry(&mut payload, &Entry { path: 0, name: "a".to_string(), hash: SHA1.to_string(), size: Some(4),
+                                           error: None, mtime: Some(700) });
+        // The second revision drops the only entry of the first
+        write_varint(&mut payload, 2500);
+        write_varint(&mut payload, 1);
+        write_varint(&mut payload, 0);
+        write_varint(&mut payload, 0);
+
+        let (docs, _) = read(&manifest(2, &payload)).unwrap();
+
+        assert_eq!(fields(&docs), vec![("dir".to_string(), "a".to_string(), SHA1.to_string(), 1500, Some(4), None, Some(700))]);
+    }
+
+    #[test]
+    fn appended_segments_continue_the_history() {
+        let mut bytes = Vec::new();
+        write_binary_entries(&mut bytes, vec![doc("dir", "a", SHA1, 1, 1000), doc("dir", "b", SHA1, 1, 1000)]).unwrap();
+
+        let (_, mut state) = read(&bytes).unwrap();
+        let second = vec![doc("dir", "b", SHA1, 1, 2000), doc("other", "c", SHA1, 1, 2000)];
+        assert!(state.can_append(&second));
+        state.write_segment(&mut bytes, second).unwrap();
+
+        let (_, mut state) = read(&bytes).unwrap();
+        assert!(!state.can_append(&[doc("dir", "a", SHA1, 1, 1500)]));
+        state.write_segment(&mut bytes, vec![doc("other", "c", SHA1, 1, 3000)]).unwrap();
+
+        let (docs, state) = read(&bytes).unwrap();
+        let names = docs.iter().map(|d| (millis(d.mod_date), d.path.as_str(), d.name.as_str())).collect::<Vec<_>>();
+        assert_eq!(names, vec![(1000, "dir", "a"), (1000, "dir", "b"), (2000, "dir", "b"), (2000, "other", "c"),
+                               (3000, "other", "c")]);
+        assert_eq!(state.last_date, Some(3000));
+    }
+
+    #[test]
+    fn rejects_newer_versions_and_bad_indices() {
+        match read(&manifest(BINARY_VERSION + 1, &[])) {
+            Err(DirdiffError::UnsupportedManifestVersion { version, .. }) => assert_eq!(version, BINARY_VERSION as u32 + 1),
+            _ => panic!("newer version accepted")
+        }
+
+        // A revision removing an entry the previous one does not have
+        let mut payload = Vec::new();
+        write_varint(&mut payload, 0);
+        write_varint(&mut payload, 1);
+        write_varint(&mut payload, 1000);
+        write_varint(&mut payload, 1);
+        write_varint(&mut payload, 0);
+        assert!(read(&manifest(BINARY_VERSION, &payload)).is_err());
+    }
+}
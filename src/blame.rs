@@ -0,0 +1,156 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::docs::Doc;
+use crate::names::join_encoded;
+use crate::report::{relative_path, Comparison};
+use crate::revisions::{describe_revision, Revisions};
+
+// What happened to a file between a revision and the one before it
+pub enum Event {
+    Appeared { path: String, hash: String },
+    Renamed { from: String, to: String },
+    Moved { from: String, to: String },
+    Changed { path: String, hash: String },
+    Disappeared { path: String }
+}
+
+// The oldest entry at `target`, a path relative to `root`
+pub fn first_appearance(entries: &[Doc], target: &str, root: &Path) -> Option<Doc> {
+    let target = relative_path(target.trim_start_matches("./"), &[root]);
+
+    entries.iter()
+        .filter(|e| relative_path(&e.full_path(), &[root]) == target)
+        .min_by_key(|e| e.mod_date)
+        .cloned()
+}
+
+// Follow `first` through the comparisons of each revision with the one before it, oldest first,
+// each paired with the date of its newer revision. Once gone, the file is only picked up again if
+// something reappears at its last path.
+pub fn timeline(first: Doc, steps: &[(SystemTime, Comparison)]) -> Vec<(SystemTime, Event)> {
+    let mut events = vec![(first.mod_date, Event::Appeared { path: first.full_path(), hash: first.hash.clone() })];
+    let mut path = first.full_path();
+    let mut present = true;
+
+    for (revision, comparison) in steps.iter().filter(|(r, _)| *r > first.mod_date) {
+        let added = comparison.added.iter().find(|d| d.full_path() == path);
+
+        if !present {
+            if let Some(doc) = added {
+                events.push((*revision, Event::Appeared { path: path.clone(), hash: doc.hash.clone() }));
+                present = true;
+            }
+            continue;
+        }
+
+        if let Some(renamed) = comparison.renamed.iter().find(|d| join_encoded(&d.doc.path, &d.prior_name) == path) {
+            events.push((*revision, Event::Renamed { from: path, to: renamed.doc.full_path() }));
+            path = renamed.doc.full_path();
+        } else if let Some(moved) = comparison.moved.iter().find(|d| join_encoded(&d.prior_path, &d.prior_name) == path) {
+            events.push((*revision, Event::Moved { from: path, to: moved.doc.full_path() }));
+            path = moved.doc.full_path();
+        } else if comparison.missing.iter().any(|d| d.full_path() == path) {
+            // Different contents at the same path show up as one file missing and another added
+            match added {
+                Some(doc) => events.push((*revision, Event::Changed { path: path.clone(), hash: doc.hash.clone() })),
+                None => {
+                    events.push((*revision, Event::Disappeared { path: path.clone() }));
+                    present = false;
+                }
+            }
+        }
+    }
+
+    events
+}
+
+pub fn print_timeline(out: &mut dyn Write, events: &[(SystemTime, Event)], root: &Path, revisions: &Revisions)
+                      -> io::Result<()> {
+    let relative = |path: &str| relative_path(path, &[root]);
+
+    for (revision, event) in events {
+        let description = match event {
+            Event::Appeared { path, hash } => format!("appeared     {}  {}", relative(path), hash),
+            Event::Renamed { from, to } => format!("renamed      {} -> {}", relative(from), relative(to)),
+            Event::Moved { from, to } => format!("moved        {} -> {}", relative(from), relative(to)),
+            Event::Changed { path, hash } => format!("changed      {}  {}", relative(path), hash),
+            Event::Disappeared { path } => format!("disappeared  {}", relative(path))
+        };
+
+        writeln!(out, "{}  {}", describe_revision(*revision, revisions), description)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::docs::{MovedDoc, RenamedDoc};
+    use crate::testutil::doc;
+
+    fn step(date: u64, change: impl FnOnce(&mut Comparison)) -> (SystemTime, Comparison) {
+        let mut comparison = Comparison { renamed: Vec::new(), moved: Vec::new(), missing: Vec::new(),
+                                          added: Vec::new(), transient: Vec::new(), collisions: Vec::new() };
+        change(&mut comparison);
+        (UNIX_EPOCH + Duration::from_millis(date), comparison)
+    }
+
+    fn describe(events: &[(SystemTime, Event)]) -> Vec<String> {
+        events.iter()
+            .map(|(revision, event)| {
+                let date = revision.duration_since(UNIX_EPOCH).unwrap().as_millis();
+                match event {
+                    Event::Appeared { path, hash } => format!("{} appeared {} {}", date, path, hash),
+                    Event::Renamed { from, to } => format!("{} renamed {} {}", date, from, to),
+                    Event::Moved { from, to } => format!("{} moved {} {}", date, from, to),
+                    Event::Changed { path, hash } => format!("{} changed {} {}", date, path, hash),
+                    Event::Disappeared { path } => format!("{} disappeared {}", date, path)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_appearance_is_the_oldest_entry_at_the_path() {
+        let entries = vec![doc("/t", "a.txt", "h2", 1, 3000), doc("/t", "a.txt", "h1", 1, 2000),
+                           doc("/t/sub", "a.txt", "h0", 1, 1000)];
+
+        let first = first_appearance(&entries, "./a.txt", Path::new("/t")).unwrap();
+        assert_eq!((first.hash.as_str(), first.path.as_str()), ("h1", "/t"));
+        assert!(first_appearance(&entries, "b.txt", Path::new("/t")).is_none());
+    }
+
+    #[test]
+    fn timeline_follows_the_file_through_every_revision() {
+        let steps = vec![
+            step(500, |c| c.missing.push(doc("/t", "a.txt", "h0", 1, 0))),
+            step(2000, |c| c.renamed.push(RenamedDoc { doc: doc("/t", "b.txt", "h1", 1, 2000),
+                                                       prior_name: "a.txt".to_string() })),
+            step(3000, |c| c.moved.push(MovedDoc { doc: doc("/t/sub", "b.txt", "h1", 1, 3000),
+                                                   prior_path: "/t".to_string(), prior_name: "b.txt".to_string() })),
+            step(4000, |c| {
+                c.missing.push(doc("/t/sub", "b.txt", "h1", 1, 3000));
+                c.added.push(doc("/t/sub", "b.txt", "h2", 2, 4000));
+            }),
+            step(5000, |c| c.missing.push(doc("/t/sub", "b.txt", "h2", 2, 4000))),
+            step(6000, |c| c.added.push(doc("/t", "a.txt", "h3", 3, 6000))),
+            step(7000, |c| c.added.push(doc("/t/sub", "b.txt", "h4", 4, 7000))),
+        ];
+
+        let events = timeline(doc("/t", "a.txt", "h1", 1, 1000), &steps);
+
+        assert_eq!(describe(&events), vec![
+            "1000 appeared /t/a.txt h1",
+            "2000 renamed /t/a.txt /t/b.txt",
+            "3000 moved /t/b.txt /t/sub/b.txt",
+            "4000 changed /t/sub/b.txt h2",
+            "5000 disappeared /t/sub/b.txt",
+            "7000 appeared /t/sub/b.txt h4",
+        ]);
+    }
+}
@@ -0,0 +1,204 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic::{commit_temp_file, lock_manifest};
+use crate::error::{DirdiffError, Result};
+use crate::manifest::{existing_format, load_latest_entries};
+use crate::names::{decode_os_str, encode_os_str};
+use crate::report::{human_bytes, total_size};
+use crate::store::{data_dir, Store};
+
+// The catalog of named trees lives in the central store when there is one, and in the XDG data
+// directory otherwise
+const CATALOG_NAME: &str = "catalog.csv";
+
+// A tracked directory registered under a name
+#[derive(Serialize, Deserialize)]
+pub struct Tree {
+    pub name: String,
+    // Canonical path, encoded like manifest names
+    pub path: String
+}
+
+impl Tree {
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(decode_os_str(&self.path))
+    }
+}
+
+pub struct Catalog {
+    pub trees: Vec<Tree>
+}
+
+fn catalog_dir(store: &Store) -> Result<PathBuf> {
+    match store {
+        Store::Central(dir) => Ok(dir.clone()),
+        Store::InTree => data_dir().ok_or_else(|| DirdiffError::Config(
+            "cannot find the data directory, neither XDG_DATA_HOME nor HOME is set".to_string()))
+    }
+}
+
+fn read_catalog(dir: &Path) -> Result<Catalog> {
+    let path = dir.join(CATALOG_NAME);
+
+    let mut reader = match ReaderBuilder::new().from_path(&path) {
+        Ok(reader) => reader,
+        Err(error) => match error.kind() {
+            csv::ErrorKind::Io(io_error) if io_error.kind() == io::ErrorKind::NotFound =>
+                return Ok(Catalog { trees: Vec::new() }),
+            _ => return Err(DirdiffError::csv(&path, error))
+        }
+    };
+
+    let trees = reader.deserialize()
+        .collect::<csv::Result<Vec<Tree>>>()
+        .map_err(|e| DirdiffError::csv(&path, e))?;
+
+    Ok(Catalog { trees })
+}
+
+// The registered trees. Without a data directory no tree can have been registered, so the
+// catalog is empty.
+pub fn load_catalog(store: &Store) -> Result<Catalog> {
+    match catalog_dir(store) {
+        Ok(dir) => read_catalog(&dir),
+        Err(_) => Ok(Catalog { trees: Vec::new() })
+    }
+}
+
+// Whether a command line argument can be the name of a tree rather than only a path
+pub fn could_be_tree_name(arg: &OsStr) -> bool {
+    arg.to_str().map(valid_tree_name).unwrap_or(false)
+}
+
+// Tree names stand in for paths on the command line, so they must not look like one
+fn valid_tree_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+impl Catalog {
+    pub fn find(&self, name: &str) -> Option<&Tree> {
+        self.trees.iter().find(|tree| tree.name == name)
+    }
+
+    // The directory a command line argument refers to: a registered tree, or else a path
+    pub fn resolve(&self, arg: &OsStr) -> PathBuf {
+        arg.to_str()
+            .and_then(|name| self.find(name))
+            .map(|tree| tree.root())
+            .unwrap_or_else(|| PathBuf::from(arg))
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(CATALOG_NAME);
+        let temp_path = dir.join(format!("{}.tmp", CATALOG_NAME));
+
+        let file = File::create(&temp_path).map_err(|e| DirdiffError::io(&temp_path, e))?;
+        let mut writer = WriterBuilder::new().from_writer(&file);
+        for tree in &self.trees {
+            writer.serialize(tree).map_err(|e| DirdiffError::csv(&temp_path, e))?;
+        }
+        writer.flush().map_err(|e| DirdiffError::io(&temp_path, e))?;
+        drop(writer);
+
+        commit_temp_file(file, &temp_path, &path)
+    }
+}
+
+// Register `root` under `name`, which must not be taken yet
+pub fn add_tree(store: &Store, name: &str, root: &Path, verbose: bool) -> Result<()> {
+    if !valid_tree_name(name) {
+        return Err(DirdiffError::InvalidTreeName(name.to_string()));
+    }
+
+    let canonical = root.canonicalize().map_err(|e| DirdiffError::io(root, e))?;
+    if !canonical.is_dir() {
+        return Err(DirdiffError::NotADirectory(root.to_path_buf()));
+    }
+
+    let dir = catalog_dir(store)?;
+    fs::create_dir_all(&dir).map_err(|e| DirdiffError::io(&dir, e))?;
+
+    // Reload under the lock so concurrent additions are not lost
+    let _lock = lock_manifest(&dir, verbose)?;
+    let mut catalog = read_catalog(&dir)?;
+
+    if catalog.find(name).is_some() {
+        return Err(DirdiffError::TreeNameTaken(name.to_string()));
+    }
+
+    if verbose { println!("Registering {} as {}", canonical.display(), name); }
+
+    catalog.trees.push(Tree { name: name.to_string(), path: encode_os_str(canonical.as_os_str()) });
+    catalog.save(&dir)
+}
+
+// Print every registered tree with the date, file count and size of its latest revision
+pub fn print_trees(out: &mut dyn Write, catalog: &Catalog, store: &Store) -> Result<()> {
+    let stdout_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+
+    for tree in &catalog.trees {
+        let root = tree.root();
+        let latest = store.manifest_dir(&root).and_then(|dir| match existing_format(&dir) {
+            Some(_) => load_latest_entries(&dir, false, false).map(Some),
+            None => Ok(None)
+        });
+
+        // One unreadable tree should not hide the others
+        let summary = match latest {
+            Ok(Some(entries)) => format!("{}  {} files  {}",
+                                         DateTime::<Local>::from(entries[0].mod_date).format("%Y-%m-%d %H:%M:%S"),
+                                         entries.len(),
+                                         human_bytes(total_size(&mut entries.iter()))),
+            Ok(None) | Err(DirdiffError::NotEnoughRevisions { .. }) => "never recorded".to_string(),
+            Err(error) => format!("error: {}", error)
+        };
+
+        writeln!(out, "{}\t{}\t{}", tree.name, root.display(), summary).map_err(stdout_error)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    #[test]
+    fn registered_names_resolve_to_their_directory() {
+        let dir = ScratchDir::new("catalog-resolve");
+        let (photos, store) = (dir.join("photos"), Store::Central(dir.join("state")));
+        fs::create_dir(&photos).unwrap();
+
+        assert!(load_catalog(&store).unwrap().trees.is_empty());
+        add_tree(&store, "photos", &photos.join("."), false).unwrap();
+
+        let catalog = load_catalog(&store).unwrap();
+        assert_eq!(catalog.resolve(OsStr::new("photos")), photos.canonicalize().unwrap());
+        // Anything else is taken as a path
+        assert_eq!(catalog.resolve(OsStr::new("music")), PathBuf::from("music"));
+        assert_eq!(catalog.resolve(OsStr::new("./photos")), PathBuf::from("./photos"));
+    }
+
+    #[test]
+    fn names_must_be_free_and_not_look_like_paths() {
+        let dir = ScratchDir::new("catalog-names");
+        let store = Store::Central(dir.join("state"));
+
+        add_tree(&store, "home", &dir, false).unwrap();
+        assert!(matches!(add_tree(&store, "home", &dir, false), Err(DirdiffError::TreeNameTaken(_))));
+
+        for name in &["", ".", "..", "a/b", "a\\b"] {
+            assert!(!could_be_tree_name(OsStr::new(name)));
+            assert!(matches!(add_tree(&store, name, &dir, false), Err(DirdiffError::InvalidTreeName(_))));
+        }
+        assert_eq!(load_catalog(&store).unwrap().trees.len(), 1);
+    }
+}
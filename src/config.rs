@@ -0,0 +1,146 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{DirdiffError, Result};
+use crate::manifest::ManifestFormat;
+use crate::store::{data_dir, Store};
+
+pub struct Config {
+    // Format used when writing manifests
+    pub manifest_format: ManifestFormat,
+    // Where manifests are kept
+    pub store: Store
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { manifest_format: ManifestFormat::Csv, store: Store::InTree }
+    }
+}
+
+// `tree` for manifests inside tracked directories, `data` for the XDG data directory, or the
+// path of a central store
+pub fn parse_store(value: &str) -> std::result::Result<Store, String> {
+    match value {
+        "" => Err("empty store".to_string()),
+        "tree" => Ok(Store::InTree),
+        "data" => data_dir()
+            .map(Store::Central)
+            .ok_or_else(|| "cannot find the data directory, neither XDG_DATA_HOME nor HOME is set".to_string()),
+        path => Ok(Store::Central(PathBuf::from(path)))
+    }
+}
+
+// $DIRDIFF_CONFIG, or dirdiff/config under the XDG config directory
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("DIRDIFF_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("dirdiff").join("config"))
+}
+
+// `#` starts a comment at the beginning of a line or after whitespace, so values may contain it
+fn strip_comment(line: &str) -> &str {
+    let mut word_start = true;
+
+    for (i, c) in line.char_indices() {
+        if c == '#' && word_start {
+            return &line[..i];
+        }
+        word_start = c.is_whitespace();
+    }
+
+    line
+}
+
+fn parse_config(path: &Path, contents: &str) -> Result<Config> {
+    let mut config = Config::default();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line_number = Some(number as u64 + 1);
+        let line = strip_comment(line).trim();
+
+        if line.is_empty() {
+            continue
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(DirdiffError::parse(path, line_number, "expected `key = value`"))
+        };
+
+        match key {
+            "manifest_format" => {
+                config.manifest_format = value.parse()
+                    .map_err(|e: String| DirdiffError::parse(path, line_number, &e))?;
+            }
+            "store" => {
+                config.store = parse_store(value)
+                    .map_err(|e| DirdiffError::parse(path, line_number, &e))?;
+            }
+            _ => return Err(DirdiffError::parse(path, line_number, &format!("unknown setting {}", key)))
+        }
+    }
+
+    Ok(config)
+}
+
+// The config file holds `key = value` lines and `#` comments. A missing file means defaults.
+pub fn load_config() -> Result<Config> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(Config::default())
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => parse_config(&path, &contents),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(error) => Err(DirdiffError::io(&path, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Config> {
+        parse_config(Path::new("config"), contents)
+    }
+
+    fn central_store(config: &Config) -> Option<&Path> {
+        match &config.store {
+            Store::Central(path) => Some(path),
+            Store::InTree => None
+        }
+    }
+
+    #[test]
+    fn comments_start_at_line_start_or_after_whitespace() {
+        let config = parse("# Keep manifests out of the tree\nstore = /srv/dirdiff#1 # shared store\n").unwrap();
+        assert_eq!(central_store(&config), Some(Path::new("/srv/dirdiff#1")));
+
+        let config = parse("store=/srv/#dirdiff\n  # indented comment\n").unwrap();
+        assert_eq!(central_store(&config), Some(Path::new("/srv/#dirdiff")));
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let config = parse("manifest_format = binary\nstore = tree\n").unwrap();
+        assert!(config.manifest_format == ManifestFormat::Binary);
+        assert!(central_store(&config).is_none());
+
+        match parse("\nstore\n") {
+            Err(DirdiffError::Parse { line, .. }) => assert_eq!(line, Some(2)),
+            _ => panic!("line without a value accepted")
+        }
+        assert!(parse("colour = blue\n").is_err());
+        assert!(parse("manifest_format = xml\n").is_err());
+    }
+}
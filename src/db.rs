@@ -1,267 +1,486 @@
+use std::collections::HashSet;
+use std::path::Path;
 use std::time::{UNIX_EPOCH, Duration};
 
 use chrono::NaiveDateTime;
 use rusqlite::{NO_PARAMS, params, Connection, Result as SqlResult};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::docs::{Doc, MovedDoc};
+use crate::docs::{Doc, MovedDoc, RenamedDoc};
+use crate::error::{DirdiffError, Result};
+use crate::names::{decode_os_str, encode_os_str};
 
-pub fn setup_working_tables(conn: &mut Connection) -> SqlResult<usize> {
+pub fn setup_working_tables(conn: &mut Connection) -> Result<usize> {
     conn.execute("CREATE TABLE working_entries (
     id  INTEGER PRIMARY KEY,
     hash TEXT NOT NULL,
     name TEXT NOT NULL,
     path TEXT NOT NULL,
-    mod_date INTEGER)", params![])?;
-    conn.execute("CREATE TABLE touched_entries (
+    mod_date INTEGER,
+    size INTEGER,
+    name_key TEXT NOT NULL,
+    path_key TEXT NOT NULL)", params![])?;
+    Ok(conn.execute("CREATE TABLE touched_entries (
     id INTEGER PRIMARY KEY,
     hash TEXT NOT NULL,
     name TEXT NOT NULL,
     path TEXT NOT NULL,
-    mod_date INTEGER)", params![])
+    mod_date INTEGER,
+    size INTEGER,
+    name_key TEXT NOT NULL,
+    path_key TEXT NOT NULL)", params![])?)
 }
 
-// Files which do not exist in revision and do not match any of the previous criteria?
-// Files that were renamed, moved, or had their content altered should be excluded
+// Files which do not exist in revision and do not match any of the previous criteria.
+// Files that were renamed, moved, or left unchanged are in touched_entries and get excluded
 pub fn missing_files(previous: &NaiveDateTime,
-                 conn: &Connection) -> Vec<Doc> {
-    let missing_sql = "SELECT *
-    FROM touched_entries EXCEPT
-    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date
-    FROM working_entries w1 LEFT JOIN working_entries w2
-    ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path = w2.path AND w1.name = w2.name
-    WHERE w1.mod_date IS NULL AND w2.id = ?1";
-
-    let mut stmt = conn.prepare(missing_sql).unwrap();
-    stmt.query_map(params![previous.timestamp()], |row| {
+                 conn: &Connection) -> Result<Vec<Doc>> {
+    let missing_sql = "SELECT id, hash, name, path, mod_date, size
+    FROM working_entries
+    WHERE mod_date = ?1 AND id NOT IN (SELECT id FROM touched_entries)";
+
+    let mut stmt = conn.prepare(missing_sql)?;
+    let rows = stmt.query_map(params![previous.timestamp()], |row| {
         Ok(Doc {
-            hash: row.get_unwrap(1),
-            name: row.get_unwrap(2),
-            path: row.get_unwrap(3),
-            //mod_date: NaiveDateTime::from_timestamp(row.get_unwrap::<usize, i64>(4), 0)
-            mod_date: UNIX_EPOCH + (Duration::from_millis(
-                row.get_unwrap::<usize, i64>(4) as u64))
+            hash: row.get(1)?,
+            name: row.get(2)?,
+            path: row.get(3)?,
+            mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(4)? as u64),
+            size: row.get::<usize, Option<i64>>(5)?.map(|s| s as u64),
+            error: None,
+            mtime: None
         })
-    }).unwrap().map(|i| i.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
 // Files that exist in the latest revision but do not exist in the previous working items
-pub fn added_files(latest: &NaiveDateTime, conn: &Connection) -> Vec<Doc> {
-    let added_sql = "SELECT w1.hash, w1.name, w1.path, w1.mod_date
+pub fn added_files(latest: &NaiveDateTime, conn: &Connection) -> Result<Vec<Doc>> {
+    let added_sql = "SELECT w1.hash, w1.name, w1.path, w1.mod_date, w1.size
     FROM
     working_entries w1 LEFT JOIN touched_entries w2
-    ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path = w2.path AND w1.name = w2.name
+    ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path_key = w2.path_key AND w1.name_key = w2.name_key
     WHERE w1.mod_date = ?1 AND w2.mod_date IS NULL";
 
-    let mut stmt = conn.prepare(added_sql).unwrap();
-    stmt.query_map(params![latest.timestamp()], |row| {
+    let mut stmt = conn.prepare(added_sql)?;
+    let rows = stmt.query_map(params![latest.timestamp()], |row| {
         Ok(Doc {
-            hash: row.get_unwrap(0),
-            name: row.get_unwrap(1),
-            path: row.get_unwrap(2),
-            mod_date: UNIX_EPOCH + (Duration::from_millis
-                (row.get_unwrap::<usize, i64>(3) as u64))
+            hash: row.get(0)?,
+            name: row.get(1)?,
+            path: row.get(2)?,
+            mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(3)? as u64),
+            size: row.get::<usize, Option<i64>>(4)?.map(|s| s as u64),
+            error: None,
+            mtime: None
         })
-    }).unwrap().map(|i| i.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
 // Same hash, different path
-pub fn moved_files(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> Vec<MovedDoc> {
-    let moved_sql = "SELECT w1.hash, w1.name, w1.path, w1.mod_date, w2.path
+pub fn moved_files(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> Result<Vec<MovedDoc>> {
+    let moved_sql = "SELECT w1.hash, w1.name, w1.path, w1.mod_date, w1.size, w2.path, w2.name
     FROM
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path != w2.path AND w1.name = w2.name
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path_key != w2.path_key AND w1.name_key = w2.name_key
     WHERE w1.mod_date = ?1 AND w2.mod_date = ?2";
 
-    let mut stmt = conn.prepare(moved_sql).unwrap();
-    stmt.query_map(params![latest.timestamp(), previous.timestamp()], |row| {
+    let mut stmt = conn.prepare(moved_sql)?;
+    let rows = stmt.query_map(params![latest.timestamp(), previous.timestamp()], |row| {
         Ok(MovedDoc {
             doc: Doc {
-                hash: row.get_unwrap(0),
-                name: row.get_unwrap(1),
-                path: row.get_unwrap(2),
-                //mod_date: NaiveDateTime::from_timestamp(row.get_unwrap::<usize, i64>(4), 0)
-                mod_date: UNIX_EPOCH + (Duration::from_millis(row.get_unwrap::<usize, i64>(3) as u64))
+                hash: row.get(0)?,
+                name: row.get(1)?,
+                path: row.get(2)?,
+                mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(3)? as u64),
+                size: row.get::<usize, Option<i64>>(4)?.map(|s| s as u64),
+                error: None,
+                mtime: None
             },
-            dest_path: row.get_unwrap(4)
+            prior_path: row.get(5)?,
+            prior_name: row.get(6)?
         })
-    }).unwrap().map(|i| i.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
-pub fn remove_moved(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &mut Connection) -> SqlResult<usize> {
-    let moved_sql = "DELETE FROM working_entries WHERE id IN (
-    SELECT w1.id
+pub fn remove_moved(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &mut Connection) -> Result<usize> {
+    // Record the prior side as touched before its latest counterpart is gone
+    let worked_entries_sql = "INSERT INTO touched_entries (id, hash, name, path, mod_date, size, name_key, path_key)
+    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date, w1.size, w1.name_key, w1.path_key
     FROM
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path != w2.path AND w1.name = w2.name
-    WHERE w1.mod_date = ?1 AND w2.mod_date = ?2)";
-    conn.execute(moved_sql, params![latest.timestamp(), previous.timestamp()])?;
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path_key != w2.path_key AND w1.name_key = w2.name_key
+    WHERE w1.mod_date = ?1 AND w2.mod_date = ?2";
+    conn.execute(worked_entries_sql, params![previous.timestamp(), latest.timestamp()])?;
 
-    let worked_entries_sql = "INSERT INTO touched_entries (id, hash, name, path, mod_date)
-    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date
+    let moved_sql = "DELETE FROM working_entries WHERE id IN (
+    SELECT w1.id
     FROM
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path != w2.path AND w1.name = w2.name
-    WHERE w1.mod_date = ?1 AND w2.mod_date = ?2";
-    conn.execute(worked_entries_sql, params![previous.timestamp(), latest.timestamp()])
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.path_key != w2.path_key AND w1.name_key = w2.name_key
+    WHERE w1.mod_date = ?1 AND w2.mod_date = ?2)";
+    Ok(conn.execute(moved_sql, params![latest.timestamp(), previous.timestamp()])?)
 }
 
 // Same hash and path, different name
-pub fn renamed_files(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> Vec<Doc> {
+pub fn renamed_files(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> Result<Vec<RenamedDoc>> {
     let renamed_sql = "SELECT *
     FROM (
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name != w2.name AND w1.path = w2.path
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key != w2.name_key AND w1.path_key = w2.path_key
         )
     WHERE w1.mod_date = ?1 and w2.mod_date = ?2";
-    let mut stmt = conn.prepare(renamed_sql).unwrap();
+    let mut stmt = conn.prepare(renamed_sql)?;
 
-    stmt.query_map(params![latest.timestamp(), previous.timestamp()], |row| {
-        Ok(Doc {
-            hash: row.get_unwrap(1),
-            name: row.get_unwrap(2),
-            path: row.get_unwrap(3),
-            //mod_date: NaiveDateTime::from_timestamp(row.get_unwrap::<usize, i64>(4), 0)
-            mod_date: UNIX_EPOCH + (Duration::from_millis(row.get_unwrap::<usize, i64>(4) as u64))
+    let rows = stmt.query_map(params![latest.timestamp(), previous.timestamp()], |row| {
+        Ok(RenamedDoc {
+            doc: Doc {
+                hash: row.get(1)?,
+                name: row.get(2)?,
+                path: row.get(3)?,
+                mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(4)? as u64),
+                size: row.get::<usize, Option<i64>>(5)?.map(|s| s as u64),
+                error: None,
+                mtime: None
+            },
+            prior_name: row.get(10)?
         })
-    }).unwrap().map(|i| i.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
-pub fn remove_renamed(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &mut Connection) -> SqlResult<usize>{
+pub fn remove_renamed(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &mut Connection) -> Result<usize>{
     let work_renamed_sql = "
-    INSERT INTO touched_entries (id, hash, name, path, mod_date)
-    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date FROM
+    INSERT INTO touched_entries (id, hash, name, path, mod_date, size, name_key, path_key)
+    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date, w1.size, w1.name_key, w1.path_key FROM
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name != w2.name AND w1.path = w2.path
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key != w2.name_key AND w1.path_key = w2.path_key
     WHERE w1.mod_date = ?1 and w2.mod_date = ?2";
-    conn.execute(work_renamed_sql, params![previous.timestamp(), latest.timestamp()])
-        .expect("Could not update touched entries");
+    conn.execute(work_renamed_sql, params![previous.timestamp(), latest.timestamp()])?;
 
     // Yes, hacky
     let renamed_sql_1 = "DELETE FROM working_entries WHERE id IN (
     SELECT w1.id FROM (
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name != w2.name AND w1.path = w2.path
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key != w2.name_key AND w1.path_key = w2.path_key
     ) WHERE w1.mod_date = ?1 and w2.mod_date = ?2)";
-    conn.execute(renamed_sql_1, params![latest.timestamp(), previous.timestamp()])
-        .expect("Could not delete outdated working entries");
+    conn.execute(renamed_sql_1, params![latest.timestamp(), previous.timestamp()])?;
 
     let renamed_sql_2 = "DELETE FROM working_entries WHERE id IN (
     SELECT w2.id FROM
         working_entries w1 INNER JOIN working_entries w2
-        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name != w2.name AND w1.path = w2.path
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key != w2.name_key AND w1.path_key = w2.path_key
     WHERE w1.mod_date = ?1 and w2.mod_date = ?2)";
-    conn.execute(renamed_sql_2, params![latest.timestamp(), previous.timestamp()])
+    Ok(conn.execute(renamed_sql_2, params![latest.timestamp(), previous.timestamp()])?)
 
 }
 
-pub fn load_working_table(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> SqlResult<usize> {
-    let load_sql = "INSERT INTO working_entries (id, hash, name, path, mod_date)
-    SELECT id, hash, name, path, mod_date
+pub fn load_working_table(latest: &NaiveDateTime, previous: &NaiveDateTime, conn: &Connection) -> Result<usize> {
+    let load_sql = "INSERT INTO working_entries (id, hash, name, path, mod_date, size, name_key, path_key)
+    SELECT id, hash, name, path, mod_date, size, name_key, path_key
     FROM dir_entries
     WHERE mod_date IN (?1, ?2)";
 
-    conn.execute(load_sql, params![latest.timestamp(), previous.timestamp()])
+    Ok(conn.execute(load_sql, params![latest.timestamp(), previous.timestamp()])?)
 }
 
-pub fn remove_unchanged_from_working_table(previous: &NaiveDateTime, conn: &mut Connection) -> SqlResult<usize> {
-    let insert_to_moved_sql = " INSERT INTO touched_entries (id, hash, name, path, mod_date)
-    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date
+pub fn remove_unchanged_from_working_table(previous: &NaiveDateTime, conn: &mut Connection) -> Result<usize> {
+    let insert_to_moved_sql = " INSERT INTO touched_entries (id, hash, name, path, mod_date, size, name_key, path_key)
+    SELECT w1.id, w1.hash, w1.name, w1.path, w1.mod_date, w1.size, w1.name_key, w1.path_key
     FROM working_entries w1 INNER JOIN working_entries w2 ON
-    (w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name = w2.name AND w1.path = w2.path)
+    (w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key = w2.name_key AND w1.path_key = w2.path_key)
     WHERE w1.mod_date = ?1";
-    conn.execute(insert_to_moved_sql, params![previous.timestamp()]).expect("Boom 1");
+    conn.execute(insert_to_moved_sql, params![previous.timestamp()])?;
 
     let unchanged_sql = "DELETE FROM working_entries WHERE id IN
     (SELECT w1.id FROM working_entries w1 INNER JOIN working_entries w2 ON
-    w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name = w2.name AND w1.path = w2.path
+    w1.mod_date != w2.mod_date AND w1.hash = w2.hash AND w1.name_key = w2.name_key AND w1.path_key = w2.path_key
     WHERE w1.mod_date = ?1)";
-    Ok(conn.execute(unchanged_sql, params![previous.timestamp()]).expect("Boom 2"))
+    Ok(conn.execute(unchanged_sql, params![previous.timestamp()])?)
 }
 
-pub fn make_local_sqlite() -> Connection {
-    Connection::open_in_memory().expect("Cannot create in-memory SQLite")
+pub fn make_local_sqlite() -> Result<Connection> {
+    Ok(Connection::open_in_memory()?)
 }
 
-pub fn create_dir_entries_table(conn: &mut Connection) -> SqlResult<usize> {
-    conn.execute("CREATE TABLE dir_entries (
+// Revision dates are stored in whole seconds, and every query reads them back as such
+pub fn create_dir_entries_table(conn: &mut Connection) -> Result<usize> {
+    Ok(conn.execute("CREATE TABLE dir_entries (
     id  INTEGER PRIMARY KEY,
     hash TEXT NOT NULL,
     name TEXT NOT NULL,
     path TEXT NOT NULL,
-    mod_date INTEGER)", params![])
+    mod_date INTEGER,
+    size INTEGER,
+    name_key TEXT NOT NULL,
+    path_key TEXT NOT NULL)", params![])?)
+}
+
+pub enum Normalization {
+    Nfc,
+    Nfd
+}
+
+// How names and paths are matched between revisions. Matching is done on keys derived from
+// them, while the original names are kept for output.
+#[derive(Default)]
+pub struct MatchOptions {
+    pub normalization: Option<Normalization>,
+    pub case_insensitive: bool
+}
+
+impl MatchOptions {
+    pub fn key(&self, name: &str) -> String {
+        let normalized = match self.normalization {
+            Some(Normalization::Nfc) => name.nfc().collect::<String>(),
+            Some(Normalization::Nfd) => name.nfd().collect::<String>(),
+            None => name.to_string()
+        };
+
+        if self.case_insensitive {
+            normalized.to_lowercase()
+        } else {
+            normalized
+        }
+    }
 }
 
-pub fn load_to_local_sqlite(conn: &mut Connection, entries: Vec<Doc>) -> SqlResult<()> {
-    let mut stmt = conn.prepare("INSERT INTO dir_entries (hash, name, path, mod_date) VALUES (?1, ?2, ?3, ?4)").unwrap();
+// Path of an entry relative to the root of its tree, so trees in different places can be matched.
+// Paths recorded under another spelling of the root are kept whole.
+fn root_relative(path: &str, roots: &[&Path]) -> String {
+    let os_path = decode_os_str(path);
+
+    roots.iter()
+        .find_map(|root| Path::new(&os_path).strip_prefix(root).ok())
+        .map(|relative| encode_os_str(relative.as_os_str()))
+        .unwrap_or_else(|| path.to_string())
+}
 
-    for entry in entries {
+// Unreadable entries have no content to compare, so they are left out. `root` is the directory
+// the entries were recorded in.
+pub fn load_to_local_sqlite(conn: &mut Connection, entries: Vec<Doc>, root: &Path, options: &MatchOptions)
+                            -> Result<()> {
+    let mut stmt = conn.prepare("INSERT INTO dir_entries (hash, name, path, mod_date, size, name_key, path_key)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+
+    let canonical = root.canonicalize().ok();
+    let roots = std::iter::once(root).chain(canonical.as_deref()).collect::<Vec<&Path>>();
+
+    for entry in entries.into_iter().filter(|e| !e.is_unreadable()) {
         let start_epoch = entry.mod_date
-            .duration_since(UNIX_EPOCH).expect("Date oopsie")
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| DirdiffError::parse(Path::new(&entry.full_path()), None,
+                                             "revision date is before 1970"))?
             .as_secs();
-        stmt.execute(&[entry.hash, entry.name, entry.path, start_epoch.to_string()]).unwrap();
+        let name_key = options.key(&entry.name);
+        let path_key = options.key(&root_relative(&entry.path, &roots));
+        stmt.execute(params![entry.hash, entry.name, entry.path, start_epoch as i64,
+                             entry.size.map(|s| s as i64), name_key, path_key])?;
     }
 
     Ok(())
 }
 
+// Entries of the same revision that only became indistinguishable through the match options,
+// grouped by what they are matched as
+pub fn name_collisions(conn: &Connection) -> Result<Vec<Vec<Doc>>> {
+    let collisions_sql = "SELECT d1.hash, d1.name, d1.path, d1.mod_date, d1.size, d1.path_key, d1.name_key
+    FROM dir_entries d1
+    WHERE EXISTS (
+        SELECT 1 FROM dir_entries d2
+        WHERE d2.mod_date = d1.mod_date AND d2.path_key = d1.path_key AND d2.name_key = d1.name_key
+        AND d2.id != d1.id)
+    ORDER BY d1.mod_date, d1.path_key, d1.name_key";
+
+    let mut stmt = conn.prepare(collisions_sql)?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        let key: (i64, String, String) = (row.get(3)?, row.get(5)?, row.get(6)?);
+        Ok((key, Doc {
+            hash: row.get(0)?,
+            name: row.get(1)?,
+            path: row.get(2)?,
+            mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(3)? as u64),
+            size: row.get::<usize, Option<i64>>(4)?.map(|s| s as u64),
+            error: None,
+            mtime: None
+        }))
+    })?;
+
+    let mut collisions: Vec<Vec<Doc>> = Vec::new();
+    let mut last_key = None;
+
+    for row in rows {
+        let (key, doc) = row?;
+
+        match collisions.last_mut() {
+            Some(group) if last_key.as_ref() == Some(&key) => group.push(doc),
+            _ => collisions.push(vec![doc])
+        }
+
+        last_key = Some(key);
+    }
+
+    // Collisions found in both revisions are only reported once
+    let mut seen = HashSet::new();
+    collisions.retain(|group| seen.insert(group.iter().map(|d| d.full_path()).collect::<Vec<String>>()));
+
+    Ok(collisions)
+}
+
+// Mark files of revision `side` whose contents are anywhere in revision `other` as touched, so
+// `missing_files` leaves only the contents `other` lacks
+pub fn touch_shared(side: &NaiveDateTime, other: &NaiveDateTime, conn: &mut Connection) -> Result<usize> {
+    let shared_sql = "INSERT INTO touched_entries (id, hash, name, path, mod_date, size, name_key, path_key)
+    SELECT DISTINCT w1.id, w1.hash, w1.name, w1.path, w1.mod_date, w1.size, w1.name_key, w1.path_key
+    FROM
+        working_entries w1 INNER JOIN working_entries w2
+        ON w1.mod_date != w2.mod_date AND w1.hash = w2.hash
+    WHERE w1.mod_date = ?1 AND w2.mod_date = ?2";
+
+    Ok(conn.execute(shared_sql, params![side.timestamp(), other.timestamp()])?)
+}
+
+// Entries sharing their hash with another entry, biggest waste of space first. Empty files are
+// left out, as their copies cost nothing.
+pub fn duplicate_entries(conn: &Connection) -> Result<Vec<Doc>> {
+    let duplicates_sql = "SELECT d.hash, d.name, d.path, d.mod_date, d.size
+    FROM dir_entries d INNER JOIN (
+        SELECT hash, COUNT(*) AS copies, MAX(size) AS size FROM dir_entries
+        WHERE size > 0
+        GROUP BY hash HAVING COUNT(*) > 1) g
+    ON d.hash = g.hash
+    ORDER BY g.size * (g.copies - 1) DESC, d.hash, d.path, d.name";
+
+    let mut stmt = conn.prepare(duplicates_sql)?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        Ok(Doc {
+            hash: row.get(0)?,
+            name: row.get(1)?,
+            path: row.get(2)?,
+            mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(3)? as u64),
+            size: row.get::<usize, Option<i64>>(4)?.map(|s| s as u64),
+            error: None,
+            mtime: None
+        })
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
+}
+
 // Get revision times in milliseconds
-pub fn revision_millis(conn: &Connection) -> Vec<i64> {
+pub fn revision_millis(conn: &Connection) -> Result<Vec<i64>> {
     let revisions_sql = "SELECT DISTINCT mod_date FROM dir_entries ORDER BY mod_date DESC";
-    let stmt = &mut conn.prepare(revisions_sql).expect("Oopsie when getting revision dates");
+    let stmt = &mut conn.prepare(revisions_sql)?;
 
-    stmt.query_map(params![], |row| {
-        let val: i64 = row.get(0).unwrap();
+    let rows = stmt.query_map(params![], |row| {
+        let val: i64 = row.get(0)?;
         Ok(val)
-    }).unwrap().map(|e| e.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
-pub fn get_doclist_from_table(table_name: &str, conn: &mut Connection) -> Vec<Doc> {
+pub fn get_doclist_from_table(table_name: &str, conn: &mut Connection) -> Result<Vec<Doc>> {
     let mut stmt = String::from("SELECT * FROM ");
     stmt += table_name;
 
-    let mut stmt = conn.prepare(&stmt).unwrap();
+    let mut stmt = conn.prepare(&stmt)?;
 
-    stmt.query_map(NO_PARAMS, |row| {
+    let rows = stmt.query_map(NO_PARAMS, |row| {
         Ok(Doc {
-            hash: row.get_unwrap(1),
-            name: row.get_unwrap(2),
-            path: row.get_unwrap(3),
-            mod_date: UNIX_EPOCH + (Duration::from_millis(row.get_unwrap::<usize, i64>(4) as u64))
+            hash: row.get(1)?,
+            name: row.get(2)?,
+            path: row.get(3)?,
+            mod_date: UNIX_EPOCH + Duration::from_secs(row.get::<usize, i64>(4)? as u64),
+            size: row.get::<usize, Option<i64>>(5)?.map(|s| s as u64),
+            error: None,
+            mtime: None
         })
-    }).unwrap().map(|i| i.unwrap()).collect()
+    })?;
+
+    Ok(rows.collect::<SqlResult<_>>()?)
 }
 
 // Print entries on a DB table that looks like a dir entry
-fn _print_entry_like(table_name: &str, conn: &mut Connection) {
+fn _print_entry_like(table_name: &str, conn: &mut Connection) -> Result<()> {
     let mut stmt = String::from("SELECT * FROM ");
     stmt += table_name;
 
-    let mut stmt = conn.prepare(&stmt).unwrap();
+    let mut stmt = conn.prepare(&stmt)?;
     let working_entries = stmt
         .query_map(NO_PARAMS, |row| {
             let doc: (i64, String, String, String, NaiveDateTime) = (
-                row.get_unwrap::<usize, i64>(0),
-                row.get_unwrap::<usize, String>(1),
-                row.get_unwrap::<usize, String>(2),
-                row.get_unwrap::<usize, String>(3),
-                NaiveDateTime::from_timestamp(row.get_unwrap::<usize, i64>(4), 0)
+                row.get::<usize, i64>(0)?,
+                row.get::<usize, String>(1)?,
+                row.get::<usize, String>(2)?,
+                row.get::<usize, String>(3)?,
+                NaiveDateTime::from_timestamp(row.get::<usize, i64>(4)?, 0)
             );
             Ok(doc)
-        }).unwrap().map(|i| i.unwrap());
+        })?;
 
     for entry in working_entries {
-        println!("{:?}", entry);
+        println!("{:?}", entry?);
     }
     println!("\n");
+
+    Ok(())
 }
 
-pub fn print_dir_entries(conn: &mut Connection) {
+pub fn print_dir_entries(conn: &mut Connection) -> Result<()> {
     const TABLE_NAME: &str = "dir_entries";
-    _print_entry_like(TABLE_NAME, conn);
+    _print_entry_like(TABLE_NAME, conn)
 }
 
-pub fn print_working_entries(conn: &mut Connection) {
+pub fn print_working_entries(conn: &mut Connection) -> Result<()> {
     const TABLE_NAME: &str = "working_entries";
-    _print_entry_like(TABLE_NAME, conn);
+    _print_entry_like(TABLE_NAME, conn)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::doc;
+
+    fn load(entries: Vec<Doc>) -> Connection {
+        let mut conn = make_local_sqlite().unwrap();
+        create_dir_entries_table(&mut conn).unwrap();
+        load_to_local_sqlite(&mut conn, entries, Path::new("/a"), &MatchOptions::default()).unwrap();
+        conn
+    }
+
+    #[test]
+    fn revision_dates_round_trip_through_sqlite() {
+        // 2020-07-14 00:00:00 UTC, long enough after the epoch that mixing up seconds and millis shows
+        let date = 1_594_684_800_000;
+        let mut conn = load(vec![doc("/a", "a.txt", "h1", 3, date)]);
+
+        assert_eq!(revision_millis(&conn).unwrap(), vec![(date / 1000) as i64]);
+
+        let entries = get_doclist_from_table("dir_entries", &mut conn).unwrap();
+        assert_eq!(entries[0].mod_date, doc("/a", "a.txt", "h1", 3, date).mod_date);
+        assert_eq!(entries[0].size, Some(3));
+    }
+
+    #[test]
+    fn duplicates_come_biggest_waste_first() {
+        let conn = load(vec![
+            doc("/a", "small-1", "small", 10, 1000),
+            doc("/a/b", "small-2", "small", 10, 1000),
+            doc("/a", "small-3", "small", 10, 1000),
+            doc("/a/b", "big-2", "big", 100, 1000),
+            doc("/a", "big-1", "big", 100, 1000),
+            doc("/a", "empty-1", "empty", 0, 1000),
+            doc("/a", "empty-2", "empty", 0, 1000),
+            doc("/a", "unique", "unique", 1000, 1000),
+        ]);
+
+        let names = duplicate_entries(&conn).unwrap().into_iter().map(|d| d.name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["big-1", "big-2", "small-1", "small-3", "small-2"]);
+    }
+}
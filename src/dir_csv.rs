@@ -1,39 +1,324 @@
+use crate::atomic::commit_temp_file;
 use crate::docs::Doc;
-use std::error::Error;
+use crate::error::{DirdiffError, Result};
+use crate::names::encode_os_str;
 use std::io;
-use std::io::prelude::*;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use std::process::exit;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::time::{UNIX_EPOCH, Duration, SystemTime};
 
-use csv::{Position, Reader, ReaderBuilder, Writer, WriterBuilder};
+use csv::{Position, Reader, ReaderBuilder, StringRecord, Writer, WriterBuilder};
 use sha1::{Digest, Sha1};
 
 use walkdir::{DirEntry, WalkDir};
 
-pub fn create_csv_writer(path: &Path, verbose: bool) -> csv::Result<Writer<File>> {
-    let path = path.join(".dirdiff.csv");
+pub const MANIFEST_NAME: &str = ".dirdiff.csv";
+
+// Manifests start with a version marker record followed by a header naming the columns.
+// Legacy manifests have neither and are read by position.
+const VERSION_MARKER: &str = "#dirdiff-manifest";
+// Version 4 added the mtime column, which revision checksums cover
+pub const MANIFEST_VERSION: u32 = 4;
+
+// Since version 3 each revision is followed by a trailer record holding its date, entry count
+// and checksum, so truncated or corrupted revisions can be detected
+pub const REVISION_MARKER: &str = "#dirdiff-revision";
+const MANIFEST_COLUMNS: [&str; 7] = ["hash", "name", "path", "mod_date", "size", "error", "mtime"];
+
+// Where each field lives in a manifest record
+pub struct Columns {
+    hash: usize,
+    name: usize,
+    path: usize,
+    mod_date: usize,
+    size: Option<usize>,
+    error: Option<usize>,
+    mtime: Option<usize>
+}
+
+impl Columns {
+    fn legacy() -> Self {
+        Columns { hash: 0, name: 1, path: 2, mod_date: 3, size: Some(4), error: Some(5), mtime: None }
+    }
+
+    fn from_header(path: &Path, header: &StringRecord) -> Result<Self> {
+        let find = |column: &str| header.iter().position(|h| h == column);
+        let require = |column: &str| find(column).ok_or_else(|| {
+            record_error(path, header, &format!("manifest header has no {} column", column))
+        });
+
+        Ok(Columns {
+            hash: require("hash")?,
+            name: require("name")?,
+            path: require("path")?,
+            mod_date: require("mod_date")?,
+            size: find("size"),
+            error: find("error"),
+            mtime: find("mtime")
+        })
+    }
+
+    pub fn to_doc(&self, path: &Path, record: &StringRecord) -> Result<Doc> {
+        let field = |index: usize, column: &str| record.get(index)
+            .map(String::from)
+            .ok_or_else(|| record_error(path, record, &format!("missing {} column", column)));
+
+        Ok(Doc {
+            hash: field(self.hash, "hash")?,
+            name: field(self.name, "name")?,
+            path: field(self.path, "path")?,
+            mod_date: record_mod_date(path, record, self)?,
+            size: record_size(path, record, self)?,
+            error: record_error_reason(record, self),
+            mtime: record_mtime(path, record, self)?
+        })
+    }
+}
+
+// A manifest reader along with where the manifest came from, for error reporting
+pub struct ManifestReader<R> {
+    pub path: PathBuf,
+    pub reader: Reader<R>,
+    pub columns: Columns,
+    // Whether the header is exactly what this version writes, so rows can be appended as is
+    current_layout: bool,
+    data_start: Position
+}
+
+// Read the version marker and header, if any, leaving the reader at the first entry
+fn open_manifest<R: io::Read + io::Seek>(path: PathBuf, mut reader: Reader<R>)
+                                          -> Result<ManifestReader<R>> {
+    let mut first = StringRecord::new();
+    let has_records = reader.read_record(&mut first).map_err(|e| DirdiffError::csv(&path, e))?;
+
+    if !has_records || first.get(0) != Some(VERSION_MARKER) {
+        reader.seek(Position::new()).map_err(|e| DirdiffError::csv(&path, e))?;
+        return Ok(ManifestReader {
+            path,
+            reader,
+            columns: Columns::legacy(),
+            current_layout: false,
+            data_start: Position::new()
+        });
+    }
+
+    let version = first.get(1)
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| record_error(&path, &first, "invalid manifest version marker"))?;
+
+    if version > MANIFEST_VERSION {
+        return Err(DirdiffError::UnsupportedManifestVersion { path, version });
+    }
+
+    let mut header = StringRecord::new();
+    if !reader.read_record(&mut header).map_err(|e| DirdiffError::csv(&path, e))? {
+        return Err(record_error(&path, &first, "manifest has no header"));
+    }
+
+    let columns = Columns::from_header(&path, &header)?;
+    let current_layout = version == MANIFEST_VERSION && header.iter().eq(MANIFEST_COLUMNS.iter().cloned());
+    let data_start = reader.position().clone();
+
+    Ok(ManifestReader { path, reader, columns, current_layout, data_start })
+}
+
+fn csv_writer<W: io::Write>(writer: W) -> Writer<W> {
+    // The version marker has fewer fields than the records after it
+    WriterBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_writer(writer)
+}
+
+fn write_preamble<W: io::Write>(writer: &mut Writer<W>) -> csv::Result<()> {
+    writer.write_record([VERSION_MARKER, &MANIFEST_VERSION.to_string()])?;
+    writer.write_record(MANIFEST_COLUMNS)
+}
+
+pub fn millis(date: SystemTime) -> u64 {
+    date.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// Running checksum of the entries of a revision, over their fields as loaded rather than their
+// exact text, so it survives rewriting the manifest
+#[derive(Default)]
+pub struct RevisionChecksum {
+    hasher: Sha1,
+    pub count: u64
+}
+
+impl RevisionChecksum {
+    pub fn add(&mut self, doc: &Doc) {
+        let size = doc.size.map(|s| s.to_string()).unwrap_or_default();
+        let fields = [&doc.hash, &doc.name, &doc.path, &millis(doc.mod_date).to_string(), &size,
+                      doc.error.as_deref().unwrap_or_default()];
+
+        for field in fields.iter() {
+            self.hasher.update(field.as_bytes());
+            self.hasher.update([0]);
+        }
+        // Added in version 4, after checksums were, so revisions without it keep their checksum
+        if let Some(mtime) = doc.mtime {
+            self.hasher.update(millis(mtime).to_string().as_bytes());
+            self.hasher.update([0]);
+        }
+        self.count += 1;
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+// The date, entry count and checksum recorded by a revision trailer
+pub fn parse_revision_trailer(record: &StringRecord) -> Option<(u64, u64, &str)> {
+    if record.get(0) != Some(REVISION_MARKER) {
+        return None;
+    }
+
+    Some((record.get(1)?.parse().ok()?, record.get(2)?.parse().ok()?, record.get(3)?))
+}
+
+fn is_revision_trailer(record: &StringRecord) -> bool {
+    record.get(0) == Some(REVISION_MARKER)
+}
+
+// A manifest being written to a temporary file, which only replaces the real one on `commit`.
+// An interrupted write therefore never leaves a partial revision behind.
+pub struct ManifestWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    writer: Writer<File>,
+    // The revision being written, trailed once an entry of another revision or the end is reached
+    revision: Option<(SystemTime, RevisionChecksum)>
+}
+
+impl ManifestWriter {
+    fn write_trailer(&mut self) -> Result<()> {
+        if let Some((date, checksum)) = self.revision.take() {
+            let count = checksum.count.to_string();
+            let record = [REVISION_MARKER, &millis(date).to_string(), &count, &checksum.finish()];
+            self.writer.write_record(record).map_err(|e| DirdiffError::csv(&self.temp_path, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn serialize(&mut self, entry: Doc) -> Result<()> {
+        if self.revision.as_ref().map(|(date, _)| *date) != Some(entry.mod_date) {
+            self.write_trailer()?;
+            self.revision = Some((entry.mod_date, RevisionChecksum::default()));
+        }
+
+        if let Some((_, checksum)) = self.revision.as_mut() {
+            checksum.add(&entry);
+        }
+
+        let temp_path = &self.temp_path;
+        self.writer.serialize(entry).map_err(|e| DirdiffError::csv(temp_path, e))
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.write_trailer()?;
+
+        let ManifestWriter { path, temp_path, mut writer, .. } = self;
+
+        writer.flush().map_err(|e| DirdiffError::io(&temp_path, e))?;
+        let file = writer.into_inner()
+            .map_err(|e| DirdiffError::io(&temp_path, io::Error::new(e.error().kind(), e.error().to_string())))?;
+        commit_temp_file(file, &temp_path, &path)
+    }
+}
+
+// Start writing the manifest of `dir`, keeping its current contents when appending. Callers are
+// expected to hold the manifest lock, as the temporary file name is fixed.
+//
+// Appending copies the whole manifest into the temporary file, so each revision costs a rewrite
+// of the history before it. That is the price of never leaving a partial revision behind.
+pub fn create_csv_writer(dir: &Path, append: bool, verbose: bool) -> Result<ManifestWriter> {
+    let path = dir.join(MANIFEST_NAME);
+    let temp_path = dir.join(format!("{}.tmp", MANIFEST_NAME));
+
+    if verbose { println!("Creating CSV {}", &path.display()); }
+
+    if append {
+        fs::copy(&path, &temp_path).map_err(|e| DirdiffError::io(&path, e))?;
+    }
+
     let file = OpenOptions::new()
-        .append(true)
+        .write(true)
         .create(true)
-        .open(&path)?;
+        .append(append)
+        .truncate(!append)
+        .open(&temp_path)
+        .map_err(|e| DirdiffError::io(&temp_path, e))?;
 
-    if verbose { println!("Creating CSV {}", &path.display()); }
-    Ok(WriterBuilder::new()
-        .has_headers(false)
-        .from_writer(file))
+    Ok(ManifestWriter { path, temp_path, writer: csv_writer(file), revision: None })
 }
 
-pub fn create_csv_reader(path: &Path, verbose: bool) -> Result<Reader<File>, csv::Error> {
-    let path = path.join(".dirdiff.csv");
-    //let file = OpenOptions::new().open(&path)?;
+// Rewrite a manifest in the current format, followed by `entries`
+pub fn rewrite_csv_entries(dir: &Path, existing: Vec<Doc>, entries: Vec<Doc>, verbose: bool) -> Result<()> {
+    if verbose { println!("Writing CSV in manifest format {}", MANIFEST_VERSION); }
+
+    let mut writer = create_csv_writer(dir, false, verbose)?;
+
+    write_preamble(&mut writer.writer).map_err(|e| DirdiffError::csv(&writer.temp_path, e))?;
+    for entry in existing.into_iter().chain(entries) {
+        writer.serialize(entry)?;
+    }
+
+    writer.commit()
+}
+
+// Append a revision to the manifest in `dir`. Legacy manifests are
+// converted to the current format first.
+pub fn write_csv_entries(dir: &Path, entries: Vec<Doc>, verbose: bool) -> Result<()> {
+    let path = dir.join(MANIFEST_NAME);
+
+    if path.exists() {
+        let manifest = create_csv_reader(dir, verbose)?;
+
+        if !manifest.current_layout {
+            let existing = load_csv_entries(manifest, verbose, false)?;
+            return rewrite_csv_entries(dir, existing, entries, verbose);
+        }
+    } else {
+        return rewrite_csv_entries(dir, Vec::new(), entries, verbose);
+    }
+
+    let mut writer = create_csv_writer(dir, true, verbose)?;
+
+    for entry in entries {
+        writer.serialize(entry)?;
+    }
+
+    writer.commit()
+}
+
+pub fn create_csv_reader(path: &Path, verbose: bool) -> Result<ManifestReader<File>> {
+    let path = path.join(MANIFEST_NAME);
 
     if verbose { println!("Opening CSV {}", &path.display()); }
 
-    ReaderBuilder::new()
+    // Legacy manifests have a varying number of columns
+    let reader = ReaderBuilder::new()
         .has_headers(false)
-        .from_path(path)
+        .flexible(true)
+        .from_path(&path)
+        .map_err(|e| DirdiffError::csv(&path, e))?;
+
+    open_manifest(path, reader)
+}
+
+// Read a manifest that was fetched into memory, e.g. from a remote host
+pub fn create_csv_reader_from_bytes(label: PathBuf, data: Vec<u8>) -> Result<ManifestReader<io::Cursor<Vec<u8>>>> {
+    // Legacy manifests have a varying number of columns
+    let reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(io::Cursor::new(data));
+
+    open_manifest(label, reader)
 }
 
 pub fn is_hidden(entry: &DirEntry) -> bool {
@@ -43,83 +328,167 @@ pub fn is_hidden(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-pub fn gen_dir_struct(path: &Path) -> io::Result<Vec<Doc>> {
+// An entry for a path that could not be read, recorded with the reason
+fn unreadable_doc(path: &Path, mod_date: SystemTime, reason: String) -> Doc {
+    Doc {
+        hash: String::new(),
+        name: path.file_name().map(encode_os_str).unwrap_or_default(),
+        path: path.parent().map(|p| encode_os_str(p.as_os_str())).unwrap_or_default(),
+        mod_date,
+        size: None,
+        error: Some(reason),
+        mtime: None
+    }
+}
+
+// SHA-1 hex digest and size of a file's contents
+pub fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut hasher = Sha1::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+// Files that cannot be read don't abort the walk, they are recorded as unreadable entries
+pub fn gen_dir_struct(path: &Path, verbose: bool, quiet: bool) -> Result<Vec<Doc>> {
     let mut dir_entries = Vec::new();
 
     let mod_date = SystemTime::now();
 
-    println!("Getting files from directory {}", &path.display());
+    if verbose { println!("Getting files from directory {}", &path.display()); }
 
     if !path.is_dir() {
-        println!("Path must be a directory");
-        exit(1);
+        return Err(DirdiffError::NotADirectory(path.to_path_buf()));
     }
 
     for entry in WalkDir::new(path).into_iter().filter_entry(|e| !is_hidden(e)) {
-        if let Ok(dir_entry) = entry {
-            let path = &dir_entry.path();
-            let dir_path = path.display();
-
-            if dir_entry.metadata()?.file_type().is_dir() {
-                println!("Entry {} is dir!", dir_path);
-                continue
+        match entry {
+            Ok(dir_entry) => {
+                let path = dir_entry.path();
+                let dir_path = path.display();
+
+                if dir_entry.file_type().is_dir() {
+                    if verbose { println!("Entry {} is dir!", dir_path); }
+                    continue
+                }
+
+                let (hash, size) = match hash_file(path) {
+                    Ok(hashed) => hashed,
+                    Err(error) => {
+                        if verbose { println!("Could not read {}: {}", dir_path, error); }
+                        dir_entries.push(unreadable_doc(path, mod_date, error.to_string()));
+                        continue
+                    }
+                };
+
+                if verbose { println!("{}", &dir_path); }
+
+                let parent = path.parent().map(|p| p.as_os_str()).unwrap_or_default();
+
+                dir_entries.push(Doc {
+                    hash,
+                    name: encode_os_str(dir_entry.file_name()),
+                    path: encode_os_str(parent),
+                    mod_date,
+                    size: Some(size),
+                    error: None,
+                    mtime: dir_entry.metadata().ok().and_then(|m| m.modified().ok())
+                });
             }
+            Err(error) => {
+                let reason = error.io_error()
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| error.to_string());
+
+                match error.path() {
+                    Some(error_path) => {
+                        if verbose { println!("Could not read {}: {}", error_path.display(), reason); }
+                        dir_entries.push(unreadable_doc(error_path, mod_date, reason));
+                    }
+                    None => if !quiet { eprintln!("Skipping unknown path: {}", reason) }
+                }
+            }
+        }
+    }
 
-            let mut data = Vec::new();
-
-            let mut fd = File::open(dir_entry.path()).unwrap();
+    Ok(dir_entries)
+}
 
-            fd.read_to_end(&mut data)?;
+fn record_error(path: &Path, record: &StringRecord, message: &str) -> DirdiffError {
+    DirdiffError::parse(path, record.position().map(|p| p.line()), message)
+}
 
-            let hash = Sha1::digest(&data);
+fn record_mod_date(path: &Path, record: &StringRecord, columns: &Columns) -> Result<SystemTime> {
+    let millis = record.get(columns.mod_date)
+        .ok_or_else(|| record_error(path, record, "missing revision date"))?
+        .parse::<u64>()
+        .map_err(|_| record_error(path, record, "revision date is not a number"))?;
 
-            println!("{}", &dir_path);
+    Ok(UNIX_EPOCH + Duration::from_millis(millis))
+}
 
-            dir_entries.push(Doc {
-                hash: hex::encode(hash),
-                name: String::from(dir_entry.file_name().to_str().unwrap()),
-                path: String::from(path.parent().unwrap().to_str().unwrap()),
-                mod_date
-            });
+// Size is a later addition, so older manifests may not have it
+fn record_size(path: &Path, record: &StringRecord, columns: &Columns) -> Result<Option<u64>> {
+    match columns.size.and_then(|i| record.get(i)) {
+        None | Some("") => Ok(None),
+        Some(size) => size.parse::<u64>()
+            .map(Some)
+            .map_err(|_| record_error(path, record, "size is not a number"))
+    }
+}
 
-        } else {
-            println!("Weird directory name");
-        }
+// File modification times are a later addition too
+fn record_mtime(path: &Path, record: &StringRecord, columns: &Columns) -> Result<Option<SystemTime>> {
+    match columns.mtime.and_then(|i| record.get(i)) {
+        None | Some("") => Ok(None),
+        Some(mtime) => mtime.parse::<u64>()
+            .map(|millis| Some(UNIX_EPOCH + Duration::from_millis(millis)))
+            .map_err(|_| record_error(path, record, "modification time is not a number"))
     }
+}
 
-    Ok(dir_entries)
+// Unreadable entries carry their error reason, other entries leave it empty
+fn record_error_reason(record: &StringRecord, columns: &Columns) -> Option<String> {
+    columns.error
+        .and_then(|i| record.get(i))
+        .filter(|e| !e.is_empty())
+        .map(String::from)
 }
 
-pub fn load_csv_entries(mut reader: Reader<File>, _verbose: bool, debug: bool) -> Vec<Doc> {
-    // reader.into_deserialize().map(|e| { let record: Doc = e.expect("Cannot parse CSV record"); record }).collect::<Vec<Doc>>()
+pub fn load_csv_entries<R: io::Read>(manifest: ManifestReader<R>, _verbose: bool, debug: bool)
+                                     -> Result<Vec<Doc>> {
+    let ManifestReader { path, mut reader, columns, .. } = manifest;
     let mut results = Vec::new();
+
     for record in reader.records() {
-        let record = record.unwrap();
-        let record = Doc {
-            hash: record[0].to_string(),
-            name: record[1].to_string(),
-            path: record[2].to_string(),
-            mod_date: UNIX_EPOCH + (Duration::from_millis(record[3].to_string().parse::<u64>().unwrap()))
-        };
-        results.push(record);
+        let record = record.map_err(|e| DirdiffError::csv(&path, e))?;
+        if !is_revision_trailer(&record) {
+            results.push(columns.to_doc(&path, &record)?);
+        }
     }
 
     if debug { println!("Loaded {} CSV records", results.len()); }
 
-    results
+    Ok(results)
 }
 
 // Same as `load_csv_entries` but only loads the last revision
-pub fn load_csv_latest_entries(mut reader: Reader<File>, verbose:bool, debug: bool) -> Result<Vec<Doc>, Box<dyn Error>> {
+pub fn load_csv_latest_entries<R: io::Read + io::Seek>(manifest: ManifestReader<R>, verbose:bool, debug: bool)
+                                                      -> Result<Vec<Doc>> {
+    let ManifestReader { path, mut reader, columns, data_start, .. } = manifest;
     let mut millis: Vec<SystemTime> = Vec::new();
+
     for item in reader.records() {
-        let item = item.unwrap();
-        millis.push(UNIX_EPOCH + Duration::from_millis(item[3].parse::<u64>().unwrap()));
+        let item = item.map_err(|e| DirdiffError::csv(&path, e))?;
+        if !is_revision_trailer(&item) {
+            millis.push(record_mod_date(&path, &item, &columns)?);
+        }
     }
 
     millis.sort();
 
-    let latest_rev = *millis.last().expect("Could not get last revision date");
+    let latest_rev = *millis.last()
+        .ok_or(DirdiffError::NotEnoughRevisions { found: 0, needed: 1 })?;
 
     if debug {
         println!("Latest revision: {:?}, from {} records", latest_rev, millis.len());
@@ -127,25 +496,19 @@ pub fn load_csv_latest_entries(mut reader: Reader<File>, verbose:bool, debug: bo
 
     let mut results = Vec::new();
 
-    reader.seek(Position::new())?;
+    reader.seek(data_start).map_err(|e| DirdiffError::csv(&path, e))?;
 
     for record in reader.records() {
-        let record = record.unwrap();
-        let mod_date = UNIX_EPOCH + (
-            Duration::from_millis(
-                record[3].to_string().parse::<u64>().unwrap()));
-
-        if mod_date == latest_rev {
-            let record = Doc {
-                hash: record[0].to_string(),
-                name: record[1].to_string(),
-                path: record[2].to_string(),
-                mod_date
-            };
-
-            results.push(record);
+        let record = record.map_err(|e| DirdiffError::csv(&path, e))?;
+        if is_revision_trailer(&record) {
+            continue;
+        }
+        let doc = columns.to_doc(&path, &record)?;
+
+        if doc.mod_date == latest_rev {
+            results.push(doc);
         } else if verbose {
-            println!("Skipped record with mod date {:?}", UNIX_EPOCH + Duration::from_millis(record[3].parse::<u64>().unwrap()))
+            println!("Skipped record with mod date {:?}", doc.mod_date)
         }
     }
 
@@ -154,3 +517,76 @@ pub fn load_csv_latest_entries(mut reader: Reader<File>, verbose:bool, debug: bo
     Ok(results)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(text: &str) -> Result<ManifestReader<io::Cursor<Vec<u8>>>> {
+        let reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(io::Cursor::new(text.as_bytes().to_vec()));
+        open_manifest(PathBuf::from("/a/.dirdiff.csv"), reader)
+    }
+
+    #[test]
+    fn malformed_records_are_parse_errors_with_their_line() {
+        match load_csv_entries(manifest("h1,a.txt,/a,1000,3\nh2,b.txt,/a,soon,4\n").unwrap(), false, false) {
+            Err(DirdiffError::Parse { path, line, .. }) => {
+                assert_eq!(path, Path::new("/a/.dirdiff.csv"));
+                assert_eq!(line, Some(2));
+            }
+            _ => panic!("malformed revision date accepted")
+        }
+
+        assert!(matches!(load_csv_entries(manifest("h1,a.txt\n").unwrap(), false, false),
+                         Err(DirdiffError::Parse { .. })));
+    }
+
+    #[test]
+    fn empty_manifests_have_no_latest_revision() {
+        match load_csv_latest_entries(manifest("").unwrap(), false, false) {
+            Err(DirdiffError::NotEnoughRevisions { found, needed }) => assert_eq!((found, needed), (0, 1)),
+            _ => panic!("empty manifest has a latest revision")
+        }
+    }
+
+    #[test]
+    fn legacy_manifests_are_read_by_position() {
+        let legacy = manifest("h1,a.txt,/a,1000\nh1,a.txt,/a,2000,3\n").unwrap();
+        assert!(!legacy.current_layout);
+
+        let docs = load_csv_latest_entries(legacy, false, false).unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].mod_date, UNIX_EPOCH + Duration::from_millis(2000));
+        assert_eq!(docs[0].size, Some(3));
+    }
+
+    #[test]
+    fn versioned_manifests_are_read_by_column_name() {
+        let current = manifest(&format!("#dirdiff-manifest,{}\n{}\n", MANIFEST_VERSION, MANIFEST_COLUMNS.join(",")))
+            .unwrap();
+        assert!(current.current_layout);
+
+        let reordered = manifest("#dirdiff-manifest,1\nmod_date,path,name,hash\n1000,/a,a.txt,h1\n").unwrap();
+        assert!(!reordered.current_layout);
+
+        let docs = load_csv_latest_entries(reordered, false, false).unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!((docs[0].hash.as_str(), docs[0].name.as_str(), docs[0].path.as_str()), ("h1", "a.txt", "/a"));
+        assert_eq!(docs[0].mod_date, UNIX_EPOCH + Duration::from_millis(1000));
+        assert_eq!(docs[0].size, None);
+
+        assert!(matches!(manifest("#dirdiff-manifest,2\nhash,name,path\n"), Err(DirdiffError::Parse { .. })));
+    }
+
+    #[test]
+    fn manifests_from_a_future_version_are_rejected() {
+        let future = format!("#dirdiff-manifest,{}\nhash,name,path,mod_date\n", MANIFEST_VERSION + 1);
+
+        match manifest(&future) {
+            Err(DirdiffError::UnsupportedManifestVersion { version, .. }) => assert_eq!(version, MANIFEST_VERSION + 1),
+            _ => panic!("future manifest accepted")
+        }
+    }
+}
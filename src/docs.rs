@@ -1,31 +1,69 @@
 use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
 use std::time::SystemTime;
 
+use crate::names::join_encoded;
+
 extern crate serde_millis;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Doc {
     pub hash: String,
     pub name: String,
     pub path: String,
     #[serde(with = "serde_millis")]
-    pub mod_date: SystemTime
+    pub mod_date: SystemTime,
+    pub size: Option<u64>,
+    // Why the file could not be read, in which case it has no hash
+    pub error: Option<String>,
+    // When the file itself was last modified, unlike `mod_date` which is when the revision was
+    // taken. Older manifests do not have it.
+    #[serde(with = "serde_millis")]
+    pub mtime: Option<SystemTime>
+}
+
+impl Doc {
+    pub fn full_path(&self) -> String {
+        join_encoded(&self.path, &self.name)
+    }
+
+    pub fn is_unreadable(&self) -> bool {
+        self.error.is_some()
+    }
 }
 
+// Same content, different directory. `prior_path` is where it lived in the older revision, and
+// `prior_name` its name there, which only differs when names are matched loosely.
 pub struct MovedDoc {
     pub doc: Doc,
-    pub dest_path: String
+    pub prior_path: String,
+    pub prior_name: String
 }
 
-pub fn print_docs(docs: Vec<Doc>) {
+// Same content and directory, different name
+pub struct RenamedDoc {
+    pub doc: Doc,
+    pub prior_name: String
+}
+
+pub fn print_docs(out: &mut dyn Write, docs: &[Doc]) -> io::Result<()> {
     for doc in docs {
-        println!("{}, {}, {}", doc.name, doc.path, doc.hash);
+        writeln!(out, "{}, {}, {}", doc.name, doc.path, doc.hash)?;
     }
+    Ok(())
 }
 
-pub fn print_moved_docs(moved_docs: Vec<MovedDoc>) {
+pub fn print_renamed_docs(out: &mut dyn Write, renamed_docs: &[RenamedDoc]) -> io::Result<()> {
+    for doc in renamed_docs {
+        writeln!(out, "{}, {}, {}", doc.doc.name, doc.doc.path, doc.doc.hash)?;
+    }
+    Ok(())
+}
+
+pub fn print_moved_docs(out: &mut dyn Write, moved_docs: &[MovedDoc]) -> io::Result<()> {
     for doc in moved_docs {
-        println!("{}/{}, {}, {} -> {}", doc.doc.path, doc.doc.name, doc.doc.hash, doc.doc.path,
-                 doc.dest_path);
+        writeln!(out, "{}/{}, {}, {} -> {}", doc.doc.path, doc.doc.name, doc.doc.hash, doc.prior_path,
+                 doc.doc.path)?;
     }
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::db::{create_dir_entries_table, duplicate_entries, load_to_local_sqlite, make_local_sqlite, MatchOptions};
+use crate::docs::Doc;
+use crate::error::Result;
+use crate::report::{human_bytes, relative_path};
+
+// Files of a revision that all have the same contents
+#[derive(Serialize)]
+pub struct DupeGroup {
+    pub hash: String,
+    pub size: u64,
+    // What removing every copy but one would free
+    pub wasted: u64,
+    pub paths: Vec<String>
+}
+
+// Group the entries of a revision by hash, biggest waste of space first
+pub fn duplicate_groups(entries: Vec<Doc>) -> Result<Vec<DupeGroup>> {
+    let mut conn = make_local_sqlite()?;
+    create_dir_entries_table(&mut conn)?;
+    load_to_local_sqlite(&mut conn, entries, Path::new(""), &MatchOptions::default())?;
+
+    let mut groups: Vec<DupeGroup> = Vec::new();
+    for doc in duplicate_entries(&conn)? {
+        match groups.last_mut() {
+            Some(group) if group.hash == doc.hash => group.paths.push(doc.full_path()),
+            _ => groups.push(DupeGroup {
+                hash: doc.hash.clone(),
+                size: doc.size.unwrap_or(0),
+                wasted: 0,
+                paths: vec![doc.full_path()]
+            })
+        }
+    }
+
+    for group in &mut groups {
+        group.wasted = group.size * (group.paths.len() as u64 - 1);
+    }
+
+    Ok(groups)
+}
+
+// The `dupes` command, as text relative to `root` or as JSON with full paths
+pub fn print_dupes(out: &mut dyn Write, groups: &[DupeGroup], root: &Path, json: bool)
+                   -> std::result::Result<(), Box<dyn Error>> {
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(groups)?)?;
+        return Ok(());
+    }
+
+    for group in groups {
+        writeln!(out, "{} wasted: {} copies of {} ({})", human_bytes(group.wasted), group.paths.len(),
+                 human_bytes(group.size), group.hash)?;
+        for path in &group.paths {
+            writeln!(out, "  {}", relative_path(path, &[root]))?;
+        }
+    }
+
+    writeln!(out, "{} wasted in {} groups of duplicates",
+             human_bytes(groups.iter().map(|g| g.wasted).sum()), groups.len())?;
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum DirdiffError {
+    Io { path: PathBuf, source: io::Error },
+    Csv { path: PathBuf, source: csv::Error },
+    Sqlite(rusqlite::Error),
+    // Malformed data, `line` being the manifest line when known
+    Parse { path: PathBuf, line: Option<u64>, message: String },
+    NotADirectory(PathBuf),
+    NotEnoughRevisions { found: usize, needed: usize },
+    NoManifest(PathBuf),
+    UnreadableFiles(usize),
+    UnsupportedManifestVersion { path: PathBuf, version: u32 },
+    Remote { host: String, path: PathBuf, message: String },
+    // An operation that cannot safely be carried out on this input
+    Refused { path: PathBuf, reason: String },
+    // A path that cannot be used where it was given, e.g. one leading out of a directory
+    InvalidPath { path: PathBuf, reason: String },
+    // Settings that are missing or inconsistent, as opposed to unreadable
+    Config(String),
+    InvalidTreeName(String),
+    TreeNameTaken(String),
+    InvalidTag(String),
+    TagTaken { tag: String, revision: String },
+    UnknownRevision(String),
+    HashMismatch { path: PathBuf, expected: String, found: String },
+    // A file where a plan expected none, or other contents than it expected
+    PathInTheWay(PathBuf)
+}
+
+pub type Result<T> = std::result::Result<T, DirdiffError>;
+
+impl DirdiffError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        DirdiffError::Io { path: path.to_path_buf(), source }
+    }
+
+    pub fn csv(path: &Path, source: csv::Error) -> Self {
+        DirdiffError::Csv { path: path.to_path_buf(), source }
+    }
+
+    pub fn parse(path: &Path, line: Option<u64>, message: &str) -> Self {
+        DirdiffError::Parse { path: path.to_path_buf(), line, message: message.to_string() }
+    }
+
+    pub fn refused(path: &Path, reason: &str) -> Self {
+        DirdiffError::Refused { path: path.to_path_buf(), reason: reason.to_string() }
+    }
+
+    pub fn invalid_path(path: &Path, reason: &str) -> Self {
+        DirdiffError::InvalidPath { path: path.to_path_buf(), reason: reason.to_string() }
+    }
+}
+
+impl fmt::Display for DirdiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirdiffError::Io { path, source } =>
+                write!(f, "{}: {}", path.display(), source),
+            DirdiffError::Csv { path, source } =>
+                write!(f, "{}: {}", path.display(), source),
+            DirdiffError::Sqlite(source) =>
+                write!(f, "Comparison database error: {}", source),
+            DirdiffError::Parse { path, line: Some(line), message } =>
+                write!(f, "{}, line {}: {}", path.display(), line, message),
+            DirdiffError::Parse { path, line: None, message } =>
+                write!(f, "{}: {}", path.display(), message),
+            DirdiffError::NotADirectory(path) =>
+                write!(f, "{}: path must be a directory", path.display()),
+            DirdiffError::NotEnoughRevisions { found, needed } =>
+                write!(f, "Need at least {} revisions to compare, found {}", needed, found),
+            DirdiffError::NoManifest(path) =>
+                write!(f, "No manifest for {}", path.display()),
+            DirdiffError::UnreadableFiles(count) =>
+                write!(f, "{} paths could not be read", count),
+            DirdiffError::UnsupportedManifestVersion { path, version } =>
+                write!(f, "{}: manifest format version {} is newer than this dirdiff supports",
+                       path.display(), version),
+            DirdiffError::Remote { host, path, message } =>
+                write!(f, "{}:{}: {}", host, path.display(), message),
+            DirdiffError::Refused { path, reason } =>
+                write!(f, "{}: {}", path.display(), reason),
+            DirdiffError::InvalidPath { path, reason } =>
+                write!(f, "{}: {}", path.display(), reason),
+            DirdiffError::Config(message) =>
+                write!(f, "{}", message),
+            DirdiffError::InvalidTreeName(name) =>
+                write!(f, "{}: tree names cannot be empty, `.`, `..` or contain path separators", name),
+            DirdiffError::TreeNameTaken(name) =>
+                write!(f, "A tree named {} is already registered", name),
+            DirdiffError::InvalidTag(tag) =>
+                write!(f, "{}: tags cannot be empty, `latest` or contain whitespace", tag),
+            DirdiffError::TagTaken { tag, revision } =>
+                write!(f, "Tag {} is already on revision {}", tag, revision),
+            DirdiffError::UnknownRevision(selector) =>
+                write!(f, "No revision matches {}", selector),
+            DirdiffError::HashMismatch { path, expected, found } =>
+                write!(f, "{}: expected contents with hash {}, found {}", path.display(), expected, found),
+            DirdiffError::PathInTheWay(path) =>
+                write!(f, "{}: already exists with other contents than planned", path.display())
+        }
+    }
+}
+
+impl Error for DirdiffError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DirdiffError::Io { source, .. } => Some(source),
+            DirdiffError::Csv { source, .. } => Some(source),
+            DirdiffError::Sqlite(source) => Some(source),
+            _ => None
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DirdiffError {
+    fn from(error: rusqlite::Error) -> Self {
+        DirdiffError::Sqlite(error)
+    }
+}
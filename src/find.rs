@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use glob::Pattern;
+
+use crate::catalog::Tree;
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::manifest::{existing_format, load_entries};
+use crate::report::relative_path;
+use crate::revisions::{describe_revision, load_revisions};
+use crate::store::Store;
+
+// What to look for in the recorded revisions
+pub enum Query {
+    // File name, wherever the file is
+    Name(Pattern),
+    // Path relative to the root of the tree
+    Path(Pattern),
+    // Start of the content hash
+    Hash(String)
+}
+
+impl Query {
+    pub fn name(glob: &str) -> Result<Query> {
+        Ok(Query::Name(parse_glob(glob)?))
+    }
+
+    pub fn path(glob: &str) -> Result<Query> {
+        Ok(Query::Path(parse_glob(glob)?))
+    }
+
+    pub fn hash(prefix: &str) -> Query {
+        Query::Hash(prefix.to_lowercase())
+    }
+
+    fn matches(&self, doc: &Doc, root: &Path) -> bool {
+        match self {
+            Query::Name(pattern) => pattern.matches(&doc.name),
+            Query::Path(pattern) => pattern.matches(&relative_path(&doc.full_path(), &[root])),
+            Query::Hash(prefix) => doc.hash.starts_with(prefix.as_str())
+        }
+    }
+}
+
+fn parse_glob(glob: &str) -> Result<Pattern> {
+    Pattern::new(glob).map_err(|e| DirdiffError::parse(Path::new(glob), None, e.msg))
+}
+
+// Print every match in one tree, oldest revision first
+fn find_in_tree(out: &mut dyn Write, tree: &Tree, dir: &Path, query: &Query, verbose: bool) -> Result<usize> {
+    let write_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+    let root = tree.root();
+    let revisions = load_revisions(dir)?;
+
+    let mut matches: BTreeMap<SystemTime, Vec<Doc>> = BTreeMap::new();
+    for doc in load_entries(dir, verbose, false)?.into_iter().filter(|d| query.matches(d, &root)) {
+        matches.entry(doc.mod_date).or_default().push(doc);
+    }
+
+    for (revision, docs) in &matches {
+        for doc in docs {
+            writeln!(out, "{}\t{}\t{}\t{}", tree.name, describe_revision(*revision, &revisions),
+                     relative_path(&doc.full_path(), &[&root]), doc.hash).map_err(write_error)?;
+        }
+    }
+
+    Ok(matches.values().map(|docs| docs.len()).sum())
+}
+
+// The `find` command: search every revision of `trees`, the catalog unless a directory was given.
+// Returns the number of matches found.
+pub fn find(out: &mut dyn Write, trees: &[Tree], store: &Store, query: &Query, verbose: bool, quiet: bool)
+            -> Result<usize> {
+    let mut found = 0;
+
+    for tree in trees {
+        let dir = match store.manifest_dir(&tree.root()) {
+            Ok(dir) if existing_format(&dir).is_some() => dir,
+            _ => continue
+        };
+
+        // One unreadable tree should not hide the matches in the others
+        match find_in_tree(out, tree, &dir, query, verbose) {
+            Ok(count) => found += count,
+            Err(error) => if !quiet { eprintln!("Skipping {}: {}", tree.name, error) }
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{write_revision, ManifestFormat};
+    use crate::names::encode_os_str;
+    use crate::testutil::{doc, ScratchDir};
+
+    // A tree named `photos` with two revisions, `b.jpg` being moved to `sub` in the second
+    fn recorded_tree(dir: &ScratchDir) -> Tree {
+        let root = encode_os_str(dir.as_os_str());
+        let sub = format!("{}/sub", root);
+
+        write_revision(dir, vec![doc(&root, "a.txt", "aa11", 1, 1000), doc(&root, "b.jpg", "bb22", 2, 1000)],
+                       ManifestFormat::Csv, None, false).unwrap();
+        write_revision(dir, vec![doc(&root, "a.txt", "aa11", 1, 2000), doc(&sub, "b.jpg", "bb22", 2, 2000)],
+                       ManifestFormat::Csv, None, false).unwrap();
+
+        Tree { name: "photos".to_string(), path: root }
+    }
+
+    fn find_lines(trees: &[Tree], query: &Query) -> Vec<String> {
+        let mut out = Vec::new();
+        let found = find(&mut out, trees, &Store::InTree, query, false, true).unwrap();
+
+        let lines = String::from_utf8(out).unwrap().lines()
+            .map(|line| {
+                // Tree, path and hash; revision dates depend on the time zone
+                let fields = line.split('\t').collect::<Vec<&str>>();
+                format!("{} {} {}", fields[0], fields[2], fields[3])
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(lines.len(), found);
+        lines
+    }
+
+    #[test]
+    fn names_match_in_every_revision() {
+        let dir = ScratchDir::new("find-name");
+        let trees = [recorded_tree(&dir)];
+
+        assert_eq!(find_lines(&trees, &Query::name("*.jpg").unwrap()), vec!["photos b.jpg bb22", "photos sub/b.jpg bb22"]);
+        assert!(find_lines(&trees, &Query::name("*.png").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn paths_match_relative_to_the_root() {
+        let dir = ScratchDir::new("find-path");
+        let trees = [recorded_tree(&dir)];
+
+        assert_eq!(find_lines(&trees, &Query::path("sub/*").unwrap()), vec!["photos sub/b.jpg bb22"]);
+        assert_eq!(find_lines(&trees, &Query::path("a.*").unwrap()).len(), 2);
+        assert!(Query::path("[").is_err());
+    }
+
+    #[test]
+    fn hashes_match_by_prefix_regardless_of_case() {
+        let dir = ScratchDir::new("find-hash");
+        let never_recorded = Tree { name: "empty".to_string(), path: encode_os_str(dir.join("empty").as_os_str()) };
+        let trees = [never_recorded, recorded_tree(&dir)];
+
+        assert_eq!(find_lines(&trees, &Query::hash("AA")), vec!["photos a.txt aa11", "photos a.txt aa11"]);
+        assert!(find_lines(&trees, &Query::hash("ab")).is_empty());
+    }
+}
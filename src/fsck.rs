@@ -0,0 +1,403 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use csv::{ByteRecord, StringRecord, WriterBuilder};
+
+use crate::atomic::lock_manifest;
+use crate::binary::read_binary_entries;
+use crate::dir_csv::*;
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::manifest::{existing_format, ManifestFormat, BINARY_MANIFEST_NAME};
+
+// Rows moved out of a manifest by `verify-manifest --quarantine`, kept for manual recovery
+pub const QUARANTINE_NAME: &str = ".dirdiff.quarantine.csv";
+
+pub struct Problem {
+    pub line: Option<u64>,
+    pub message: String
+}
+
+// Outcome of checking a manifest: what is wrong with it, and how it splits into rows worth
+// keeping and rows to quarantine
+pub struct ManifestCheck {
+    pub path: PathBuf,
+    pub rows: usize,
+    pub revisions: usize,
+    pub problems: Vec<Problem>,
+    kept: Vec<Doc>,
+    quarantined: Vec<ByteRecord>,
+    // Binary manifests are decoded as a whole, so their broken parts cannot be set aside
+    repairable: bool
+}
+
+impl ManifestCheck {
+    fn new(path: PathBuf, repairable: bool) -> Self {
+        ManifestCheck {
+            path,
+            rows: 0,
+            revisions: 0,
+            problems: Vec::new(),
+            kept: Vec::new(),
+            quarantined: Vec::new(),
+            repairable
+        }
+    }
+
+    fn problem(&mut self, line: Option<u64>, message: String) {
+        self.problems.push(Problem { line, message });
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// A well-formed row waiting for the trailer of its revision
+struct PendingRow {
+    line: u64,
+    raw: ByteRecord,
+    doc: Doc
+}
+
+// Rows read since the last revision trailer
+#[derive(Default)]
+struct PendingRevision {
+    rows: Vec<PendingRow>,
+    checksum: RevisionChecksum,
+    // Whether some of its rows were already found broken, which explains a checksum mismatch
+    broken_rows: bool
+}
+
+fn line_of(record: &ByteRecord) -> u64 {
+    record.position().map(|p| p.line()).unwrap_or(0)
+}
+
+fn check_trailer(check: &mut ManifestCheck, pending: PendingRevision, trailer: &StringRecord) {
+    let line = line_of(trailer.as_byte_record());
+    let PendingRevision { rows, checksum, broken_rows } = pending;
+
+    let (date, count, expected) = match parse_revision_trailer(trailer) {
+        Some(fields) => fields,
+        None => {
+            check.problem(Some(line), "malformed revision trailer".to_string());
+            check.kept.extend(rows.into_iter().map(|r| r.doc));
+            return;
+        }
+    };
+
+    check.revisions += 1;
+
+    if broken_rows {
+        // The broken rows are already quarantined; the rest of the revision is still usable
+        check.kept.extend(rows.into_iter().map(|r| r.doc));
+    } else if count != checksum.count || checksum.finish() != expected {
+        // Some row changed without becoming unreadable, and there is no telling which one
+        check.problem(Some(line), format!("revision {} does not match its checksum ({} of {} entries present)",
+                                         date, rows.len(), count));
+        check.quarantined.extend(rows.into_iter().map(|r| r.raw));
+    } else {
+        check.kept.extend(rows.into_iter().map(|r| r.doc));
+    }
+}
+
+fn check_csv_manifest(dir: &Path, verbose: bool) -> Result<ManifestCheck> {
+    let manifest = create_csv_reader(dir, verbose)?;
+    let mut check = ManifestCheck::new(manifest.path.clone(), true);
+    let ManifestReader { path, mut reader, columns, .. } = manifest;
+
+    let contents = fs::read(&path).map_err(|e| DirdiffError::io(&path, e))?;
+    let ends_with_newline = !matches!(contents.last(), Some(byte) if *byte != b'\n');
+
+    let mut seen: HashSet<(u64, String, String)> = HashSet::new();
+    let mut pending = PendingRevision::default();
+    let mut has_trailers = false;
+    let mut raw = ByteRecord::new();
+
+    while reader.read_byte_record(&mut raw).map_err(|e| DirdiffError::csv(&path, e))? {
+        let line = line_of(&raw);
+
+        let record = match StringRecord::from_byte_record(raw.clone()) {
+            Ok(record) => record,
+            Err(_) => {
+                check.rows += 1;
+                check.problem(Some(line), "row is not valid UTF-8".to_string());
+                check.quarantined.push(raw.clone());
+                pending.broken_rows = true;
+                continue;
+            }
+        };
+
+        if record.get(0) == Some(REVISION_MARKER) {
+            has_trailers = true;
+            check_trailer(&mut check, pending, &record);
+            pending = PendingRevision::default();
+            continue;
+        }
+
+        check.rows += 1;
+
+        let doc = match columns.to_doc(&path, &record) {
+            Ok(doc) => doc,
+            Err(DirdiffError::Parse { message, .. }) => {
+                check.problem(Some(line), message);
+                check.quarantined.push(raw.clone());
+                pending.broken_rows = true;
+                continue;
+            }
+            Err(error) => return Err(error)
+        };
+
+        // The checksum covers every row as written, duplicates included
+        pending.checksum.add(&doc);
+
+        if !seen.insert((millis(doc.mod_date), doc.path.clone(), doc.name.clone())) {
+            check.problem(Some(line), format!("duplicate entry for {} in revision {}",
+                                             doc.full_path(), millis(doc.mod_date)));
+            check.quarantined.push(raw.clone());
+            continue;
+        }
+
+        pending.rows.push(PendingRow { line, raw: raw.clone(), doc });
+    }
+
+    if !pending.rows.is_empty() {
+        if has_trailers {
+            // Every complete revision ends with a trailer, so these rows were cut short
+            let first_line = pending.rows[0].line;
+            check.problem(Some(first_line), format!("truncated trailing revision: {} rows without a revision trailer",
+                                                    pending.rows.len()));
+            check.quarantined.extend(pending.rows.into_iter().map(|r| r.raw));
+        } else {
+            // Without trailers, only a last row missing its line end gives a truncation away
+            let last = if ends_with_newline { None } else { pending.rows.pop() };
+            check.kept.extend(pending.rows.into_iter().map(|r| r.doc));
+
+            if let Some(last) = last {
+                check.problem(Some(last.line), "truncated last row".to_string());
+                check.quarantined.push(last.raw);
+            }
+
+            let dates = check.kept.iter().map(|d| d.mod_date).collect::<HashSet<_>>();
+            check.revisions = dates.len();
+        }
+    }
+
+    Ok(check)
+}
+
+fn check_binary_manifest(dir: &Path, verbose: bool) -> Result<ManifestCheck> {
+    let path = dir.join(BINARY_MANIFEST_NAME);
+    let mut check = ManifestCheck::new(path.clone(), false);
+
+    if verbose { println!("Checking binary manifest {}", &path.display()); }
+
+    let file = File::open(&path).map_err(|e| DirdiffError::io(&path, e))?;
+    let entries = match read_binary_entries(&path, &mut BufReader::new(file)) {
+        Ok(entries) => entries,
+        Err(DirdiffError::UnsupportedManifestVersion { path, version }) =>
+            return Err(DirdiffError::UnsupportedManifestVersion { path, version }),
+        Err(DirdiffError::Io { source, .. }) => {
+            check.problem(None, source.to_string());
+            return Ok(check);
+        }
+        Err(DirdiffError::Parse { message, .. }) => {
+            check.problem(None, message);
+            return Ok(check);
+        }
+        Err(error) => return Err(error)
+    };
+
+    let mut seen: HashSet<(u64, &str, &str)> = HashSet::new();
+    let mut duplicates = Vec::new();
+    for doc in &entries {
+        if !seen.insert((millis(doc.mod_date), &doc.path, &doc.name)) {
+            duplicates.push(format!("duplicate entry for {} in revision {}", doc.full_path(), millis(doc.mod_date)));
+        }
+    }
+
+    check.rows = entries.len();
+    check.revisions = seen.iter().map(|(date, _, _)| *date).collect::<HashSet<_>>().len();
+    for duplicate in duplicates {
+        check.problem(None, duplicate);
+    }
+
+    Ok(check)
+}
+
+// Validate every entry of the manifest of `dir`
+pub fn check_manifest(dir: &Path, verbose: bool) -> Result<ManifestCheck> {
+    match existing_format(dir) {
+        Some(ManifestFormat::Binary) => check_binary_manifest(dir, verbose),
+        _ => check_csv_manifest(dir, verbose)
+    }
+}
+
+fn append_quarantine(dir: &Path, rows: &[ByteRecord]) -> Result<()> {
+    let path = dir.join(QUARANTINE_NAME);
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .map_err(|e| DirdiffError::io(&path, e))?;
+
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(&file);
+    for row in rows {
+        writer.write_byte_record(row).map_err(|e| DirdiffError::csv(&path, e))?;
+    }
+
+    writer.flush().map_err(|e| DirdiffError::io(&path, e))?;
+    drop(writer);
+    file.sync_all().map_err(|e| DirdiffError::io(&path, e))
+}
+
+// Move the broken rows found by `check` to the quarantine file and rewrite the manifest
+// without them. Returns the number of rows moved.
+pub fn quarantine_rows(dir: &Path, check: ManifestCheck, verbose: bool) -> Result<usize> {
+    if !check.repairable {
+        return Err(DirdiffError::refused(&check.path, "binary manifests cannot be repaired row by row"));
+    }
+
+    let moved = check.quarantined.len();
+
+    // Rows are copied out before the manifest loses them, so a crash in between loses nothing
+    append_quarantine(dir, &check.quarantined)?;
+    rewrite_csv_entries(dir, check.kept, Vec::new(), verbose)?;
+
+    Ok(moved)
+}
+
+// The `verify-manifest` command. Returns whether any problems were found.
+pub fn verify_manifest(dir: &Path, quarantine: bool, verbose: bool, quiet: bool) -> Result<bool> {
+    // Hold off writers so the check and any repair see the same manifest
+    let _lock = lock_manifest(dir, verbose)?;
+    let check = check_manifest(dir, verbose)?;
+
+    if !quiet {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        print_check(&mut out, &check).map_err(|e| DirdiffError::io(Path::new("<stdout>"), e))?;
+    }
+
+    if check.is_ok() {
+        return Ok(false);
+    }
+
+    if quarantine {
+        let moved = quarantine_rows(dir, check, verbose)?;
+        if !quiet { println!("Moved {} rows to {}", moved, dir.join(QUARANTINE_NAME).display()); }
+    } else if !quiet && check.repairable {
+        println!("Run again with --quarantine to move {} rows to {} and rewrite the manifest without them",
+                 check.quarantined.len(), QUARANTINE_NAME);
+    }
+
+    Ok(true)
+}
+
+fn print_check(out: &mut dyn Write, check: &ManifestCheck) -> io::Result<()> {
+    for problem in &check.problems {
+        match problem.line {
+            Some(line) => writeln!(out, "{}, line {}: {}", check.path.display(), line, problem.message)?,
+            None => writeln!(out, "{}: {}", check.path.display(), problem.message)?
+        }
+    }
+
+    writeln!(out, "Checked {} entries in {} revisions: {} problems found",
+             check.rows, check.revisions, check.problems.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::testutil::{self, ScratchDir};
+
+    fn doc(name: &str, size: u64, date: u64, mtime: u64) -> Doc {
+        let mut doc = testutil::doc("dir", name, &format!("hash-{}", name), size, date);
+        doc.mtime = Some(UNIX_EPOCH + Duration::from_millis(mtime));
+        doc
+    }
+
+    // A directory holding a manifest of two revisions, the second with a single entry
+    fn manifest_dir(test: &str) -> ScratchDir {
+        let dir = ScratchDir::new(&format!("fsck-{}", test));
+
+        write_csv_entries(&dir, vec![doc("a", 11111, 1000, 500), doc("b", 22222, 1000, 600)], false).unwrap();
+        write_csv_entries(&dir, vec![doc("a", 11111, 2000, 500)], false).unwrap();
+        dir
+    }
+
+    fn edit_manifest(dir: &Path, edit: impl Fn(String) -> String) {
+        let path = dir.join(MANIFEST_NAME);
+        let contents = fs::read_to_string(&path).unwrap();
+        let edited = edit(contents.clone());
+        assert_ne!(edited, contents);
+        fs::write(&path, edited).unwrap();
+    }
+
+    fn messages(check: &ManifestCheck) -> Vec<&str> {
+        check.problems.iter().map(|p| p.message.as_str()).collect()
+    }
+
+    #[test]
+    fn intact_revisions_match_their_trailers() {
+        let dir = manifest_dir("intact");
+        let contents = fs::read_to_string(dir.join(MANIFEST_NAME)).unwrap();
+        assert_eq!(contents.matches(REVISION_MARKER).count(), 2);
+
+        let check = check_manifest(&dir, false).unwrap();
+        assert!(check.is_ok(), "{:?}", messages(&check));
+        assert_eq!((check.rows, check.revisions), (3, 2));
+    }
+
+    #[test]
+    fn edited_rows_fail_the_checksum_of_their_revision() {
+        let dir = manifest_dir("edited");
+        edit_manifest(&dir, |contents| contents.replacen("22222", "22223", 1));
+
+        let check = check_manifest(&dir, false).unwrap();
+        assert_eq!(messages(&check), vec!["revision 1000 does not match its checksum (2 of 2 entries present)"]);
+        assert_eq!(check.quarantined.len(), 2);
+
+        // Only the other revision is left once the edited one is set aside
+        assert_eq!(quarantine_rows(&dir, check, false).unwrap(), 2);
+        let check = check_manifest(&dir, false).unwrap();
+        assert!(check.is_ok(), "{:?}", messages(&check));
+        assert_eq!((check.rows, check.revisions), (1, 1));
+        assert_eq!(fs::read_to_string(dir.join(QUARANTINE_NAME)).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn checksums_cover_modification_times() {
+        let dir = manifest_dir("mtime");
+        edit_manifest(&dir, |contents| contents.replacen(",600\n", ",601\n", 1));
+
+        let check = check_manifest(&dir, false).unwrap();
+        assert_eq!(messages(&check), vec!["revision 1000 does not match its checksum (2 of 2 entries present)"]);
+    }
+
+    #[test]
+    fn revisions_without_their_trailer_were_cut_short() {
+        let dir = manifest_dir("truncated");
+        edit_manifest(&dir, |contents| {
+            let trailer = contents.rfind(REVISION_MARKER).unwrap();
+            contents[..trailer].to_string()
+        });
+
+        let check = check_manifest(&dir, false).unwrap();
+        assert_eq!(messages(&check), vec!["truncated trailing revision: 1 rows without a revision trailer"]);
+        assert_eq!((check.rows, check.revisions), (3, 1));
+    }
+
+    #[test]
+    fn malformed_trailers_are_reported() {
+        let dir = manifest_dir("malformed");
+        edit_manifest(&dir, |contents| contents.replacen(&format!("{},2000,1,", REVISION_MARKER),
+                                                         &format!("{},2000,x,", REVISION_MARKER), 1));
+
+        let check = check_manifest(&dir, false).unwrap();
+        assert_eq!(messages(&check), vec!["malformed revision trailer"]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::Local;
+
+use crate::report::{human_bytes, relative_path, split_path, Change, ChangeType, Comparison};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin: 0.5em 0 1em 1em; }
+th, td { text-align: left; padding: 0.2em 0.8em; border-bottom: 1px solid #ddd; }
+td.size { text-align: right; white-space: nowrap; }
+td.hash { font-family: monospace; color: #666; }
+details { margin: 0.3em 0; }
+details.category > summary { font-size: 1.2em; font-weight: bold; }
+details.dir { margin-left: 1em; }
+details.dir > summary { font-family: monospace; }
+#filter { width: 30em; padding: 0.3em; margin-bottom: 1em; }
+.added { color: #1a7f37; }
+.missing { color: #cf222e; }
+.renamed { color: #9a6700; }
+.moved { color: #0969da; }
+.transient { color: #8250df; }
+.collision { color: #bc4c00; }
+";
+
+// Hide rows not matching the filter, and directories left with no visible rows
+const SCRIPT: &str = "
+document.getElementById('filter').addEventListener('input', function (event) {
+    var needle = event.target.value.toLowerCase();
+    document.querySelectorAll('tr[data-path]').forEach(function (row) {
+        row.hidden = row.dataset.path.toLowerCase().indexOf(needle) === -1;
+    });
+    document.querySelectorAll('details.dir').forEach(function (dir) {
+        dir.hidden = dir.querySelector('tr[data-path]:not([hidden])') === null;
+    });
+});
+";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn category(change_type: &ChangeType) -> (&'static str, &'static str) {
+    match change_type {
+        ChangeType::Added => ("added", "Added"),
+        ChangeType::Missing => ("missing", "Missing"),
+        ChangeType::Renamed => ("renamed", "Renamed"),
+        ChangeType::Moved => ("moved", "Moved"),
+        ChangeType::Transient => ("transient", "Transient"),
+        ChangeType::Collision => ("collision", "Name collisions")
+    }
+}
+
+// Changes of a directory, and its subdirectories by name
+#[derive(Default)]
+struct DirTree<'a, 'b> {
+    changes: Vec<&'b Change<'a>>,
+    dirs: BTreeMap<String, DirTree<'a, 'b>>
+}
+
+impl<'a, 'b> DirTree<'a, 'b> {
+    fn insert(&mut self, dir: &str, change: &'b Change<'a>) {
+        let node = dir.split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .fold(self, |node, component| node.dirs.entry(component.to_string()).or_default());
+        node.changes.push(change);
+    }
+}
+
+fn print_changes(out: &mut dyn Write, changes: &[&Change], roots: &[&Path]) -> io::Result<()> {
+    writeln!(out, "<table><tr><th>Name</th><th>Previously</th><th>Size</th><th>Hash</th></tr>")?;
+
+    for change in changes {
+        let new_path = change.new_path.as_ref().map(|p| relative_path(p, roots));
+        let old_path = change.old_path.as_ref().map(|p| relative_path(p, roots));
+        let (_, name) = split_path(&relative_path(change.path(), roots));
+        let previously = match change.change_type {
+            ChangeType::Renamed | ChangeType::Moved => old_path.clone().unwrap_or_default(),
+            _ => String::new()
+        };
+        let filter_path = [old_path, new_path].iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        writeln!(out, "<tr data-path=\"{}\"><td>{}</td><td>{}</td><td class=\"size\">{}</td>\
+                      <td class=\"hash\">{}</td></tr>",
+                 escape(&filter_path), escape(&name), escape(&previously),
+                 change.size.map(human_bytes).unwrap_or_default(), escape(change.hash))?;
+    }
+
+    writeln!(out, "</table>")
+}
+
+// One collapsible element per directory, nested like the directories themselves
+fn print_dir(out: &mut dyn Write, tree: &DirTree, roots: &[&Path]) -> io::Result<()> {
+    if !tree.changes.is_empty() {
+        print_changes(out, &tree.changes, roots)?;
+    }
+
+    for (name, dir) in &tree.dirs {
+        writeln!(out, "<details class=\"dir\" open><summary>{}/</summary>", escape(name))?;
+        print_dir(out, dir, roots)?;
+        writeln!(out, "</details>")?;
+    }
+
+    Ok(())
+}
+
+// Single static page with a summary table and one collapsible tree per change category
+pub fn print_html(out: &mut dyn Write, comparison: &Comparison, roots: &[&Path]) -> io::Result<()> {
+    let roots_label = roots.iter()
+        .map(|r| escape(&r.display().to_string()))
+        .collect::<Vec<String>>()
+        .join(" / ");
+    let changes = comparison.changes();
+    // Only comparisons across several revisions can have transient files
+    let mut change_types = vec![ChangeType::Added, ChangeType::Missing, ChangeType::Renamed, ChangeType::Moved];
+    if !comparison.transient.is_empty() {
+        change_types.push(ChangeType::Transient);
+    }
+    if !comparison.collisions.is_empty() {
+        change_types.push(ChangeType::Collision);
+    }
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Dirdiff report: {}</title>", roots_label)?;
+    writeln!(out, "<style>{}</style></head><body>", STYLE)?;
+    writeln!(out, "<h1>Dirdiff report: {}</h1>", roots_label)?;
+    writeln!(out, "<p>Generated {}</p>", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+
+    writeln!(out, "<table><tr><th>Change</th><th>Files</th><th>Bytes</th></tr>")?;
+    for change_type in &change_types {
+        let (class, label) = category(change_type);
+        let matching = changes.iter()
+            .filter(|c| &c.change_type == change_type)
+            .collect::<Vec<&Change>>();
+        let bytes: u64 = matching.iter().filter_map(|c| c.size).sum();
+        writeln!(out, "<tr><td class=\"{}\">{}</td><td>{}</td><td class=\"size\">{}</td></tr>",
+                 class, label, matching.len(), human_bytes(bytes))?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<input id=\"filter\" type=\"search\" placeholder=\"Filter by path\">")?;
+
+    for change_type in &change_types {
+        let (class, label) = category(change_type);
+        let mut tree = DirTree::default();
+        let mut count = 0;
+
+        for change in changes.iter().filter(|c| &c.change_type == change_type) {
+            let (dir, _) = split_path(&relative_path(change.path(), roots));
+            tree.insert(&dir, change);
+            count += 1;
+        }
+
+        writeln!(out, "<details class=\"category\" open><summary class=\"{}\">{} ({})</summary>",
+                 class, label, count)?;
+        print_dir(out, &tree, roots)?;
+        writeln!(out, "</details>")?;
+    }
+
+    writeln!(out, "<script>{}</script>", SCRIPT)?;
+    writeln!(out, "</body></html>")
+}
@@ -1,22 +1,84 @@
-use std::error;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use chrono::NaiveDateTime;
-use clap::{Arg, ArgMatches, App};
+use clap::{App, Arg, ArgGroup, ArgMatches};
 use rusqlite::Connection;
 
+mod apply;
+mod atomic;
+mod binary;
+mod blame;
+mod catalog;
+mod config;
 mod dir_csv;
 mod docs;
 mod db;
+mod dupes;
+mod error;
+mod find;
+mod fsck;
+mod html;
+mod manifest;
+mod names;
+mod plan;
+mod overlap;
+mod prune;
+mod report;
+mod revisions;
+mod store;
+#[cfg(test)]
+mod testutil;
+mod verify;
 
 use crate::dir_csv::*;
+use crate::catalog::*;
+use crate::config::{load_config, parse_store};
 use crate::docs::*;
 use crate::db::*;
+use crate::error::DirdiffError;
+use crate::manifest::*;
+use crate::names::encode_os_str;
+use crate::prune::RetentionPolicy;
+use crate::report::*;
+use crate::revisions::*;
+use crate::store::Store;
+use crate::verify::{print_verify_report, verify_files, Sample};
 
 const RECORD:&str = "record";
 const HISTORY:&str = "history";
 const COMPARE_LOCAL:&str = "local";
 const COMPARE_REMOTE:&str = "remote";
+const STATUS:&str = "status";
+const CONVERT:&str = "convert";
+const VERIFY_MANIFEST:&str = "verify-manifest";
+const ADD:&str = "add";
+const LIST:&str = "list";
+const PRUNE:&str = "prune";
+const TAG:&str = "tag";
+const LOG:&str = "log";
+const VERIFY:&str = "verify";
+const DUPES:&str = "dupes";
+const OVERLAP:&str = "overlap";
+const FIND:&str = "find";
+const BLAME:&str = "blame";
+const PLAN:&str = "plan";
+const APPLY:&str = "apply";
+
+const EXIT_NO_DIFFERENCES: i32 = 0;
+const EXIT_DIFFERENCES: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+    0  no differences found
+    1  differences found
+    2  operational error";
 
 fn timestamps_to_datetimes(rev_millis: Vec<i64>) -> Vec<NaiveDateTime> {
     rev_millis.iter()
@@ -25,8 +87,8 @@ fn timestamps_to_datetimes(rev_millis: Vec<i64>) -> Vec<NaiveDateTime> {
 }
 
 // Compare latest revision with prior revision of same local directory
-fn history(conn: &mut Connection, verbose: bool, debug: bool) -> Result<(), Box<dyn error::Error>>{
-    let revision_millis = revision_millis(conn);
+fn history(conn: &mut Connection, verbose: bool, debug: bool) -> Result<Comparison, Box<dyn Error>>{
+    let revision_millis = revision_millis(conn)?;
 
     if debug { println!("Got the following revision millis from DB data: {:?}", revision_millis) ;}
 
@@ -34,6 +96,10 @@ fn history(conn: &mut Connection, verbose: bool, debug: bool) -> Result<(), Box<
 
     if debug { println!("Found the following revision dates: {:?}", revisions); }
 
+    if revisions.len() < 2 {
+        return Err(DirdiffError::NotEnoughRevisions { found: revisions.len(), needed: 2 }.into());
+    }
+
     let latest_revision = revisions[0];
     let prior_revision = revisions[1];
 
@@ -42,150 +108,476 @@ fn history(conn: &mut Connection, verbose: bool, debug: bool) -> Result<(), Box<
         println!("Prior revision at {}", &prior_revision);
     }
 
-    setup_working_tables(conn)
-        .expect("Could not create working table for revision comparison");
-    let inserted = load_working_table(&latest_revision, &prior_revision, conn)
-        .expect("Could not load directory entries to working table");
+    setup_working_tables(conn)?;
+    let inserted = load_working_table(&latest_revision, &prior_revision, conn)?;
 
     if  debug{
         println!("Inserted {} records into working table", inserted);
 
         if inserted > 0 {
-            let working_records = get_doclist_from_table("working_entries", conn);
+            let working_records = get_doclist_from_table("working_entries", conn)?;
             println!("Initial working records:");
-            print_docs(working_records);
+            print_docs(&mut io::stdout(), &working_records)?;
         }
     }
 
-    remove_unchanged_from_working_table(&prior_revision, conn)
-        .expect("Could not remove unchanged directory entries from working table");
+    remove_unchanged_from_working_table(&prior_revision, conn)?;
 
     if  debug {
         println!("Remaining entries after removing unchanged:");
-        db::print_working_entries(conn);
+        db::print_working_entries(conn)?;
     }
 
-    let renamed = renamed_files(&latest_revision, &prior_revision, conn);
-    remove_renamed(&latest_revision, &prior_revision, conn)
-        .expect("Could not remove renamed entries from working table");
+    let renamed = renamed_files(&latest_revision, &prior_revision, conn)?;
+    remove_renamed(&latest_revision, &prior_revision, conn)?;
 
-    if !renamed.is_empty() {
-        println!("Renamed files:");
-        print_docs(renamed);
-    } else {
-        println!("No renamed filed");
+    if debug {
+        println!("Remaining after removing renamed:");
+        db::print_working_entries(conn)?;
     }
 
+    let moved = moved_files(&latest_revision, &prior_revision, conn)?;
+    remove_moved(&latest_revision, &prior_revision, conn)?;
+
     if debug {
-        println!("Remaining after removing renamed:");
-        db::print_working_entries(conn);
+        println!("Remaining after moved:");
+        db::print_working_entries(conn)?;
     }
 
-    let moved = moved_files(&latest_revision, &prior_revision, conn);
+    let missing = missing_files(&prior_revision, conn)?;
+    let added = added_files(&latest_revision, conn)?;
 
-    if !moved.is_empty() {
-        println!("Moved files:");
-        print_moved_docs(moved);
-    } else {
-        println!("No moved files");
+    let collisions = name_collisions(conn)?;
+
+    Ok(Comparison { renamed, moved, missing, added, transient: Vec::new(), collisions })
+}
+
+// Compare each revision with the one before it, oldest first, pairing each comparison with the
+// date of its newer revision
+fn pairwise_comparisons(command: &ArgMatches, entries: Vec<Doc>, root: &Path, verbose: bool, debug: bool)
+                        -> Result<Vec<(SystemTime, Comparison)>, Box<dyn Error>> {
+    let mut by_revision: BTreeMap<SystemTime, Vec<Doc>> = BTreeMap::new();
+    for entry in entries {
+        by_revision.entry(entry.mod_date).or_default().push(entry);
     }
 
-    remove_moved(&latest_revision, &prior_revision, conn)?;
+    let options = match_options(command);
+    let mut comparisons = Vec::new();
+    let mut prior: Option<Vec<Doc>> = None;
 
-    if debug {
-        println!("Remaining after moved:");
-        db::print_working_entries(conn);
+    for (revision, docs) in by_revision {
+        if let Some(prior_docs) = prior.replace(docs.clone()) {
+            let mut conn = make_local_sqlite()?;
+            create_dir_entries_table(&mut conn)?;
+            load_to_local_sqlite(&mut conn, prior_docs, root, &options)?;
+            load_to_local_sqlite(&mut conn, docs, root, &options)?;
+            comparisons.push((revision, history(&mut conn, verbose, debug)?));
+        }
     }
 
-    let missing = missing_files(&prior_revision, conn);
+    Ok(comparisons)
+}
 
-    if !missing.is_empty() {
-        println!("Missing files:");
-        print_docs(missing);
-    } else {
-        println!("No missing files");
+fn match_options(command: &ArgMatches) -> MatchOptions {
+    MatchOptions {
+        normalization: match command.value_of("normalize-unicode") {
+            Some("nfc") => Some(Normalization::Nfc),
+            Some("nfd") => Some(Normalization::Nfd),
+            _ => None
+        },
+        case_insensitive: command.is_present("case-insensitive")
     }
+}
 
-    let added = added_files(&latest_revision, conn);
+// Plans have no report to list name collisions in, so they get a warning instead
+fn warn_collisions(conn: &Connection, quiet: bool) -> Result<(), Box<dyn Error>> {
+    if quiet { return Ok(()); }
 
-    if !added.is_empty() {
-        println!("Added files:");
-        print_docs(added);
-    } else {
-        println!("No added files");
+    for collision in name_collisions(conn)? {
+        let paths = collision.iter().map(|d| d.full_path()).collect::<Vec<String>>();
+        eprintln!("Warning: these paths are treated as the same file: {}", paths.join(", "));
     }
 
     Ok(())
 }
 
-fn setup_history(command: &ArgMatches,
-        verbose: bool, debug: bool)  -> Result<(), Box<dyn error::Error>> {
+fn output_format(command: &ArgMatches) -> Result<OutputFormat, Box<dyn Error>> {
+    Ok(command.value_of("format").unwrap_or("text").parse::<OutputFormat>()?)
+}
+
+// A directory argument, given either as a path or as the name of a tree in the catalog. The
+// catalog is only read for arguments that can be a tree name.
+fn dir_arg(command: &ArgMatches, name: &str, store: &Store) -> Result<PathBuf, Box<dyn Error>> {
+    let arg = command.value_of_os(name).unwrap();
+
+    if could_be_tree_name(arg) {
+        Ok(load_catalog(store)?.resolve(arg))
+    } else {
+        Ok(PathBuf::from(arg))
+    }
+}
+
+// The revisions picked by --from and --to, or by --range FROM..TO, by default the latest
+// revision and the one before it
+fn history_range(command: &ArgMatches, dir: &Path, entries: &[Doc])
+                 -> Result<(SystemTime, SystemTime), Box<dyn Error>> {
+    let revisions = entries.iter().map(|e| e.mod_date).collect::<BTreeSet<SystemTime>>();
+    if revisions.len() < 2 {
+        return Err(DirdiffError::NotEnoughRevisions { found: revisions.len(), needed: 2 }.into());
+    }
+
+    let (from, to) = match command.value_of("range") {
+        Some(range) => match range.find("..") {
+            Some(i) => (Some(&range[..i]).filter(|f| !f.is_empty()), Some(&range[i + 2..]).filter(|t| !t.is_empty())),
+            None => return Err(format!("Invalid range {}, expected FROM..TO", range).into())
+        },
+        None => (command.value_of("from"), command.value_of("to"))
+    };
+
+    let revision_info = load_revisions(dir)?;
+    let to = revision_info.resolve(to.unwrap_or("latest"), &revisions)?;
+    let from = match from {
+        Some(from) => revision_info.resolve(from, &revisions)?,
+        None => *revisions.range(..to).next_back()
+            .ok_or(DirdiffError::NotEnoughRevisions { found: 1, needed: 2 })?
+    };
+
+    if from >= to {
+        return Err(format!("Revision {} is not before revision {}", format_revision(from), format_revision(to)).into());
+    }
+
+    Ok((from, to))
+}
+
+// Files of the revisions strictly between `from` and `to` whose contents neither of them has,
+// each listed once for every path it had
+fn transient_files(entries: &[Doc], from: SystemTime, to: SystemTime) -> Vec<Doc> {
+    let kept = entries.iter()
+        .filter(|e| e.mod_date == from || e.mod_date == to)
+        .map(|e| e.hash.as_str())
+        .collect::<BTreeSet<&str>>();
+    let mut seen = BTreeSet::new();
+
+    entries.iter()
+        .filter(|e| e.mod_date > from && e.mod_date < to && !e.is_unreadable())
+        .filter(|e| !kept.contains(e.hash.as_str()) && seen.insert((e.full_path(), e.hash.clone())))
+        .cloned()
+        .collect()
+}
+
+fn setup_history(command: &ArgMatches, store: &Store,
+        verbose: bool, debug: bool, quiet: bool)  -> Result<bool, Box<dyn Error>> {
     if verbose {
         println!("Comparing latest revision with prior to check for changes.");
     }
 
-    let root = Path::new(command.value_of_os("comp_dir").unwrap());
+    let root: &Path = &dir_arg(command, "comp_dir", store)?;
+
+    let dir = store.manifest_dir(root)?;
+    let entries = load_entries(&dir, verbose, debug)?;
+    let (from, to) = history_range(command, &dir, &entries)?;
+    let entries = entries.into_iter().filter(|e| e.mod_date >= from && e.mod_date <= to).collect::<Vec<Doc>>();
+    let transient = transient_files(&entries, from, to);
 
-    let mut conn = make_local_sqlite();
-    let reader = create_csv_reader(root, verbose)?;
-    let entries = load_csv_entries(reader, verbose, debug);
+    let mut steps = Vec::new();
+    if command.is_present("stepwise") {
+        let revisions = entries.iter().map(|e| e.mod_date).collect::<BTreeSet<SystemTime>>();
+        let comparisons = pairwise_comparisons(command, entries.clone(), root, verbose, debug)?;
+        for (prior, (revision, comparison)) in revisions.iter().zip(comparisons) {
+            steps.push((format!("Changes from {} to {}", format_revision(*prior), format_revision(revision)),
+                        comparison));
+        }
+    }
+
+    let mut conn = make_local_sqlite()?;
 
     create_dir_entries_table(&mut conn)?;
-    load_to_local_sqlite(&mut conn, entries)?;
-    history(&mut conn, verbose, debug)?;
+    load_to_local_sqlite(&mut conn, entries.into_iter().filter(|e| e.mod_date == from || e.mod_date == to).collect(),
+                         root, &match_options(command))?;
+    let mut comparison = history(&mut conn, verbose, debug)?;
+    comparison.transient = transient;
+    let differences = !comparison.is_empty();
 
-    Ok(())
+    if !quiet {
+        if steps.is_empty() {
+            output_comparison(command, &comparison, &[root])?;
+        } else {
+            steps.push((format!("Net changes from {} to {}", format_revision(from), format_revision(to)), comparison));
+            output_stepwise(command, &steps, &[root])?;
+        }
+    }
+
+    Ok(differences)
 }
 
-fn setup_compare_local(command: &ArgMatches, verbose: bool, debug: bool)
-                       -> Result<(), Box<dyn error::Error>> {
+fn setup_compare_local(command: &ArgMatches, store: &Store, verbose: bool, debug: bool, quiet: bool)
+                       -> Result<bool, Box<dyn Error>> {
     if verbose {
         println!("Compare the latest revision of directories");
     }
 
-    let first = Path::new(command.value_of_os("first").unwrap());
-    let first_reader = create_csv_reader(first, verbose)?;
-    let first_entries = load_csv_latest_entries(first_reader, verbose, debug)?;
+    let first: &Path = &dir_arg(command, "first", store)?;
+    let first_entries = load_latest_entries(&store.manifest_dir(first)?, verbose, debug)?;
+
+    let second: &Path = &dir_arg(command, "second", store)?;
+    let second_entries = load_latest_entries(&store.manifest_dir(second)?, verbose, debug)?;
+
+    compare_entries(command, first_entries, second_entries, &[first, second], verbose, debug, quiet)
+}
+
+fn setup_compare_remote(command: &ArgMatches, store: &Store, verbose: bool, debug: bool, quiet: bool)
+                        -> Result<bool, Box<dyn Error>> {
+    if verbose {
+        println!("Compare the latest revision of a local and a remote directory");
+    }
+
+    let local: &Path = &dir_arg(command, "local_directory", store)?;
+    let local_entries = load_latest_entries(&store.manifest_dir(local)?, verbose, debug)?;
+
+    let host = command.value_of("remote_host").unwrap();
+    let remote = Path::new(command.value_of_os("remote_directory").unwrap());
+    let remote_entries = load_remote_latest_entries(host, remote, verbose, debug)?;
+
+    compare_entries(command, local_entries, remote_entries, &[local, remote], verbose, debug, quiet)
+}
+
+// Compare the files currently on disk with the latest recorded revision
+fn setup_status(command: &ArgMatches, store: &Store, verbose: bool, debug: bool, quiet: bool)
+                -> Result<bool, Box<dyn Error>> {
+    if verbose {
+        println!("Compare the directory contents with its latest revision");
+    }
+
+    let root: &Path = &dir_arg(command, "directory", store)?;
+    let recorded_entries = load_latest_entries(&store.manifest_dir(root)?, verbose, debug)?;
+    let mut current_entries = gen_dir_struct(root, verbose, quiet)?;
 
-    let second = Path::new(command.value_of_os("second").unwrap());
-    let second_reader = create_csv_reader(second, verbose)?;
-    let second_entries = load_csv_latest_entries(second_reader, verbose, debug)?;
+    // Revisions are told apart by the second they were taken in, so keep the scan after the record
+    if let Some(recorded_date) = recorded_entries.first().map(|e| e.mod_date) {
+        let scan_date = recorded_date + Duration::from_secs(1);
+        for entry in current_entries.iter_mut().filter(|e| e.mod_date < scan_date) {
+            entry.mod_date = scan_date;
+        }
+    }
+
+    compare_entries(command, recorded_entries, current_entries, &[root], verbose, debug, quiet)
+}
 
-    let mut conn = make_local_sqlite();
+// Plan how to make the target a copy of the source, comparing their latest revisions as if the
+// target were an older revision of the source
+fn setup_plan(command: &ArgMatches, store: &Store, verbose: bool, debug: bool, quiet: bool)
+              -> Result<bool, Box<dyn Error>> {
+    let source: &Path = &dir_arg(command, "source", store)?;
+    let target: &Path = &dir_arg(command, "target", store)?;
+
+    // A directory recorded while empty has no entries, which is no reason not to sync it
+    let latest_entries = |root: &Path| match load_latest_entries(&store.manifest_dir(root)?, verbose, debug) {
+        Err(DirdiffError::NotEnoughRevisions { found: 0, .. }) => Ok(Vec::new()),
+        entries => entries
+    };
+    let source_entries = plan::relative_entries(latest_entries(source)?, source, UNIX_EPOCH + Duration::from_secs(2))?;
+    let target_entries = plan::relative_entries(latest_entries(target)?, target, UNIX_EPOCH + Duration::from_secs(1))?;
+
+    let comparison = if source_entries.is_empty() || target_entries.is_empty() {
+        let readable = |entries: &[Doc]| entries.iter().filter(|e| !e.is_unreadable()).cloned().collect();
+        Comparison { renamed: Vec::new(), moved: Vec::new(), missing: readable(&target_entries),
+                     added: readable(&source_entries), transient: Vec::new(), collisions: Vec::new() }
+    } else {
+        let mut conn = make_local_sqlite()?;
+        create_dir_entries_table(&mut conn)?;
+        let options = match_options(command);
+        load_to_local_sqlite(&mut conn, target_entries.clone(), Path::new(""), &options)?;
+        load_to_local_sqlite(&mut conn, source_entries.clone(), Path::new(""), &options)?;
+        warn_collisions(&conn, quiet)?;
+        history(&mut conn, verbose, debug)?
+    };
+
+    let plan = plan::build_plan(comparison, &source_entries, &target_entries, source, target)?;
+    if !quiet {
+        match command.value_of("emit") {
+            Some("sh") => plan::print_shell_script(&mut io::stdout(), &plan)?,
+            Some(_) => plan::print_rsync_list(&mut io::stdout(), &plan)?,
+            None => plan::print_plan(&mut io::stdout(), &plan, command.value_of("format") == Some("json"))?
+        }
+    }
+
+    Ok(!plan.operations.is_empty())
+}
+
+fn compare_entries(command: &ArgMatches, first_entries: Vec<Doc>, second_entries: Vec<Doc>,
+                   roots: &[&Path], verbose: bool, debug: bool, quiet: bool)
+                   -> Result<bool, Box<dyn Error>> {
+    let mut conn = make_local_sqlite()?;
     create_dir_entries_table(&mut conn)?;
-    load_to_local_sqlite(&mut conn, first_entries)?;
-    load_to_local_sqlite(&mut conn, second_entries)?;
+    let options = match_options(command);
+    load_to_local_sqlite(&mut conn, first_entries, roots[0], &options)?;
+    // Status compares a tree with itself, so has a single root
+    load_to_local_sqlite(&mut conn, second_entries, roots[roots.len() - 1], &options)?;
+
+    if debug { db::print_dir_entries(&mut conn)?; }
 
-    if debug { db::print_dir_entries(&mut conn); }
+    let comparison = history(&mut conn, verbose, debug)?;
+    if !quiet { output_comparison(command, &comparison, roots)?; }
 
-    history(&mut conn, verbose, debug)?;
+    Ok(!comparison.is_empty())
+}
+
+// Where comparisons go: stdout, or the file given with --output. Only the terminal gets colors.
+fn comparison_output(command: &ArgMatches) -> Result<(Box<dyn Write>, bool), Box<dyn Error>> {
+    match command.value_of_os("output") {
+        Some(output) => Ok((Box::new(File::create(output)?), false)),
+        None => Ok((Box::new(io::stdout()), atty::is(atty::Stream::Stdout)))
+    }
+}
+
+// Write the comparison to stdout, or to the file given with --output
+fn output_comparison(command: &ArgMatches, comparison: &Comparison, roots: &[&Path])
+                     -> Result<(), Box<dyn Error>> {
+    let format = output_format(command)?;
+    let (mut out, colored) = comparison_output(command)?;
+
+    print_comparison(&mut out, comparison, &format, roots, colored)?;
+    out.flush()?;
 
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn error::Error>> {
-    let args = App::new("Dirdiff")
+// Write several comparisons one after the other, each under its heading
+fn output_stepwise(command: &ArgMatches, comparisons: &[(String, Comparison)], roots: &[&Path])
+                   -> Result<(), Box<dyn Error>> {
+    let format = output_format(command)?;
+    if let OutputFormat::Json | OutputFormat::Ndjson | OutputFormat::Html = format {
+        return Err("--stepwise only supports text and tree output".into());
+    }
+
+    let (mut out, colored) = comparison_output(command)?;
+    for (heading, comparison) in comparisons {
+        writeln!(out, "{}:", heading)?;
+        print_comparison(&mut out, comparison, &format, roots, colored)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+fn format_arg() -> Arg<'static> {
+    Arg::with_name("format")
+        .long("format")
+        .about("Output format")
+        .takes_value(true)
+        .possible_values(&["text", "json", "ndjson", "tree", "html"])
+        .default_value("text")
+}
+
+fn normalize_arg() -> Arg<'static> {
+    Arg::with_name("normalize-unicode")
+        .long("normalize-unicode")
+        .about("Match names after Unicode normalization")
+        .takes_value(true)
+        .possible_values(&["nfc", "nfd"])
+}
+
+fn case_insensitive_arg() -> Arg<'static> {
+    Arg::with_name("case-insensitive")
+        .long("case-insensitive")
+        .about("Match names regardless of case")
+}
+
+fn output_arg() -> Arg<'static> {
+    Arg::with_name("output")
+        .long("output")
+        .about("Write the comparison to this file instead of stdout")
+        .takes_value(true)
+}
+
+fn keep_arg(name: &'static str, about: &'static str) -> Arg<'static> {
+    Arg::with_name(name)
+        .long(name)
+        .about(about)
+        .takes_value(true)
+        .value_name("N")
+}
+
+fn retention_policy(command: &ArgMatches) -> Result<RetentionPolicy, Box<dyn Error>> {
+    let count = |name: &str| -> Result<usize, Box<dyn Error>> {
+        match command.value_of(name) {
+            Some(value) => value.parse::<usize>().map_err(|_| format!("--{} must be a number", name).into()),
+            None => Ok(0)
+        }
+    };
+
+    Ok(RetentionPolicy {
+        last: count("keep-last")?,
+        daily: count("keep-daily")?,
+        weekly: count("keep-weekly")?,
+        monthly: count("keep-monthly")?,
+        yearly: count("keep-yearly")?
+    })
+}
+
+fn app() -> App<'static> {
+    App::new("Dirdiff")
         .author("Andres Osinski <andres.osinski@gmail.com>")
         .about("Compare local and remote directory file revisions")
+        .after_help(EXIT_CODES_HELP)
         .arg(Arg::with_name("v")
             .short('v')
             .about("Verbosity"))
         .arg(Arg::with_name("d")
             .short('d')
             .about("Debug"))
+        .arg(Arg::with_name("q")
+            .short('q')
+            .long("quiet")
+            .about("Print nothing, only report through the exit code"))
+        .arg(Arg::with_name("store")
+            .long("store")
+            .about("Keep manifests in this central directory instead of the tracked directories; \
+                    `data` for the XDG data directory")
+            .takes_value(true)
+            .global(true))
         .subcommand(App::new(RECORD)
             .about("Record local directory revision")
             .arg(Arg::with_name("directory")
                 .about("The directory to record revision for")
                 .index(1)
-                .required(true)))
+                .required(true))
+            .arg(Arg::with_name("strict")
+                .long("strict")
+                .about("Exit with an error if any path could not be read"))
+            .arg(Arg::with_name("message")
+                .long("message")
+                .short('m')
+                .about("Describe the revision")
+                .takes_value(true)))
         .subcommand(App::new(HISTORY)
             .about("Compare the latest directory revision with the previous one")
             .arg(Arg::with_name("comp_dir")
                 .about("The directory to compare revisions")
                 .index(1)
-                .required(true)
-            ))
+                .required(true))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .about("Revision to compare from, by tag, date or `latest`; defaults to the one before --to")
+                .takes_value(true))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .about("Revision to compare to, by tag, date or `latest`; defaults to the latest")
+                .takes_value(true))
+            .arg(Arg::with_name("range")
+                .long("range")
+                .about("Revisions to compare, as FROM..TO, reporting files that came and went in between as transient")
+                .takes_value(true)
+                .value_name("FROM..TO")
+                .conflicts_with_all(&["from", "to"]))
+            .arg(Arg::with_name("stepwise")
+                .long("stepwise")
+                .about("Also show the changes between each revision in the range and the next"))
+            .arg(format_arg())
+            .arg(output_arg())
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
         .subcommand(App::new(COMPARE_LOCAL)
             .about("Compare two directories in this host")
             .arg(Arg::with_name("first")
@@ -193,7 +585,11 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                 .required(true))
             .arg(Arg::with_name("second")
                 .index(2)
-                .required(true)))
+                .required(true))
+            .arg(format_arg())
+            .arg(output_arg())
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
         .subcommand(App::new(COMPARE_REMOTE)
             .about("Compare the latest revisions of two different directories")
             .arg(Arg::with_name("local_directory")
@@ -204,35 +600,495 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                 .required(true))
             .arg(Arg::with_name("remote_directory")
                 .index(3)
+                .required(true))
+            .arg(format_arg())
+            .arg(output_arg())
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
+        .subcommand(App::new(STATUS)
+            .about("Compare a directory with its latest revision")
+            .arg(Arg::with_name("directory")
+                .about("The directory to check")
+                .index(1)
+                .required(true))
+            .arg(format_arg())
+            .arg(output_arg())
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
+        .subcommand(App::new(CONVERT)
+            .about("Convert the manifest of a directory to another format")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose manifest to convert")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .about("Manifest format to convert to")
+                .takes_value(true)
+                .possible_values(&["csv", "binary"])
                 .required(true)))
-        .get_matches();
+        .subcommand(App::new(VERIFY_MANIFEST)
+            .about("Check the manifest of a directory for malformed, duplicate or truncated entries")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose manifest to check")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("quarantine")
+                .long("quarantine")
+                .about("Move broken rows out of the manifest, into .dirdiff.quarantine.csv")))
+        .subcommand(App::new(ADD)
+            .about("Register a directory in the catalog under a name usable in place of its path")
+            .arg(Arg::with_name("name")
+                .about("The name of the tree")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("directory")
+                .about("The directory to register")
+                .index(2)
+                .required(true)))
+        .subcommand(App::new(LIST)
+            .about("List the trees in the catalog with their latest revision"))
+        .subcommand(App::new(PRUNE)
+            .about("Remove old revisions from the manifest of a directory")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose manifest to prune")
+                .index(1)
+                .required(true))
+            .arg(keep_arg("keep-last", "Keep the latest N revisions"))
+            .arg(keep_arg("keep-daily", "Keep the latest revision of each of the last N days with one"))
+            .arg(keep_arg("keep-weekly", "Keep the latest revision of each of the last N weeks with one"))
+            .arg(keep_arg("keep-monthly", "Keep the latest revision of each of the last N months with one"))
+            .arg(keep_arg("keep-yearly", "Keep the latest revision of each of the last N years with one"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .about("Only show which revisions would be removed")))
+        .subcommand(App::new(TAG)
+            .about("Tag a revision so it can be referred to by name and is never pruned")
+            .arg(Arg::with_name("revision")
+                .about("The revision to tag, by date, existing tag or `latest`")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("name")
+                .about("The tag")
+                .index(2)
+                .required(true))
+            .arg(Arg::with_name("directory")
+                .about("The directory whose revision to tag")
+                .index(3)
+                .default_value(".")))
+        .subcommand(App::new(VERIFY)
+            .about("Re-hash files to find ones whose contents changed while their size and modification time did not")
+            .arg(Arg::with_name("directory")
+                .about("The directory to verify against its latest revision")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("sample")
+                .long("sample")
+                .about("Only verify a random sample of the files, e.g. 5% or 1000")
+                .validator(|sample| sample.parse::<Sample>())
+                .takes_value(true)))
+        .subcommand(App::new(DUPES)
+            .about("List files with identical contents, most wasted space first")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose duplicates to list")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("rev")
+                .long("rev")
+                .about("The revision to look in, by date, tag or `latest`")
+                .takes_value(true)
+                .default_value("latest"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .about("Output format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")))
+        .subcommand(App::new(OVERLAP)
+            .about("Show which contents of two directories exist in the other, wherever they are there")
+            .arg(Arg::with_name("first")
+                .about("The first directory")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("second")
+                .about("The second directory")
+                .index(2)
+                .required(true)))
+        .subcommand(App::new(FIND)
+            .about("Search every revision of the trees in the catalog for files")
+            .arg(Arg::with_name("directory")
+                .about("Only search this directory, which need not be in the catalog")
+                .index(1))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .about("Find files whose name matches this glob")
+                .takes_value(true)
+                .value_name("GLOB"))
+            .arg(Arg::with_name("path")
+                .long("path")
+                .about("Find files whose path in their tree matches this glob")
+                .takes_value(true)
+                .value_name("GLOB"))
+            .arg(Arg::with_name("hash")
+                .long("hash")
+                .about("Find files whose hash starts with this prefix")
+                .takes_value(true)
+                .value_name("PREFIX"))
+            .group(ArgGroup::with_name("query")
+                .args(&["name", "path", "hash"])
+                .required(true)))
+        .subcommand(App::new(BLAME)
+            .about("Trace a file through every revision, following its renames and moves")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose revisions to search")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("file")
+                .about("The path of the file, relative to the directory")
+                .index(2)
+                .required(true))
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
+        .subcommand(App::new(PLAN)
+            .about("Plan the operations that make a target directory a copy of a source directory")
+            .arg(Arg::with_name("source")
+                .about("The directory to copy from")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("target")
+                .about("The directory to bring up to date")
+                .index(2)
+                .required(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .about("Output format")
+                .takes_value(true)
+                .possible_values(&["text", "json"]))
+            .arg(Arg::with_name("emit")
+                .long("emit")
+                .about("Instead, print a shell script doing all but the transfers, or the files to transfer for rsync")
+                .takes_value(true)
+                .possible_values(&["sh", "rsync"])
+                .conflicts_with("format"))
+            .arg(normalize_arg())
+            .arg(case_insensitive_arg()))
+        .subcommand(App::new(APPLY)
+            .about("Carry out a plan made with `plan --format json` against its target directory")
+            .arg(Arg::with_name("plan")
+                .about("The plan file")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("no-trash")
+                .long("no-trash")
+                .about("Delete files outright instead of moving them to .dirdiff-trash in the target"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .about("Only show what would be done")))
+        .subcommand(App::new(LOG)
+            .about("List the revisions of a directory with their tags and messages")
+            .arg(Arg::with_name("directory")
+                .about("The directory whose revisions to list")
+                .index(1)
+                .required(true)))
+}
 
-    let verbose = args.is_present("v");
-    let debug = args.is_present("d");
+// Returns whether any differences were found
+fn run() -> Result<bool, Box<dyn Error>> {
+    let args = match app().try_get_matches() {
+        Ok(args) => args,
+        Err(error) if error.use_stderr() => {
+            eprintln!("{}", error);
+            exit(EXIT_ERROR);
+        }
+        Err(error) => error.exit()
+    };
+
+    let quiet = args.is_present("q");
+    let verbose = args.is_present("v") && !quiet;
+    let debug = args.is_present("d") && !quiet;
+    let config = load_config()?;
+    let store = match args.value_of("store") {
+        Some(store) => parse_store(store)?,
+        None => config.store.clone()
+    };
 
     if let Some(record) = args.subcommand_matches(RECORD) {
-        let root = Path::new(record.value_of_os("directory").unwrap());
-
-        match gen_dir_struct(&root) {
-            Err(error) =>
-                println!("Messed up here: {}", &error),
-            Ok(dir_entries) => {
-                let mut writer = create_csv_writer(&root, verbose)
-                    .expect("Error creating CSV writer");
-                for entry in dir_entries {
-                    writer.serialize(entry).expect("Error writing CSV record");
+        let root: &Path = &dir_arg(record, "directory", &store)?;
+
+        let dir_entries = gen_dir_struct(root, verbose, quiet)?;
+        let unreadable = dir_entries.iter()
+            .filter(|e| e.is_unreadable())
+            .map(|e| format!("{}: {}", e.full_path(), e.error.as_ref().unwrap()))
+            .collect::<Vec<String>>();
+
+        write_revision(&store.create_manifest_dir(root)?, dir_entries, config.manifest_format,
+                       record.value_of("message"), verbose)?;
+
+        if !unreadable.is_empty() {
+            if !quiet {
+                eprintln!("Skipped {} unreadable paths:", unreadable.len());
+                for path in &unreadable {
+                    eprintln!("  {}", path);
                 }
-                writer.flush().unwrap();
+            }
+
+            if record.is_present("strict") {
+                return Err(DirdiffError::UnreadableFiles(unreadable.len()).into());
             }
         }
+
+        Ok(false)
     } else if let Some(command) = args.subcommand_matches(HISTORY) {
-        setup_history(command, verbose, debug)?;
+        setup_history(command, &store, verbose, debug, quiet)
     } else if let Some(command) = args.subcommand_matches(COMPARE_LOCAL) {
-        setup_compare_local(command, verbose, debug)?;
+        setup_compare_local(command, &store, verbose, debug, quiet)
+    } else if let Some(command) = args.subcommand_matches(COMPARE_REMOTE) {
+        setup_compare_remote(command, &store, verbose, debug, quiet)
+    } else if let Some(command) = args.subcommand_matches(STATUS) {
+        setup_status(command, &store, verbose, debug, quiet)
+    } else if let Some(command) = args.subcommand_matches(CONVERT) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let format = command.value_of("to").unwrap().parse::<ManifestFormat>()?;
+        convert_manifest(&store.manifest_dir(root)?, format, verbose)?;
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(ADD) {
+        let root = Path::new(command.value_of_os("directory").unwrap());
+        add_tree(&store, command.value_of("name").unwrap(), root, verbose)?;
+        Ok(false)
+    } else if args.subcommand_matches(LIST).is_some() {
+        if !quiet { print_trees(&mut io::stdout(), &load_catalog(&store)?, &store)?; }
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(PRUNE) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let policy = retention_policy(command)?;
+        let mut out: Box<dyn Write> = if quiet { Box::new(io::sink()) } else { Box::new(io::stdout()) };
+        prune::prune(&mut out, &store.manifest_dir(root)?, &policy, command.is_present("dry-run"), verbose)?;
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(TAG) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let name = command.value_of("name").unwrap();
+        let revision = tag_revision(&store.manifest_dir(root)?, command.value_of("revision").unwrap(), name, verbose)?;
+        if !quiet { println!("Tagged revision {} as {}", format_revision(revision), name); }
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(VERIFY) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let sample = match command.value_of("sample") {
+            Some(sample) => sample.parse::<Sample>()?,
+            None => Sample::All
+        };
+        let entries = load_latest_entries(&store.manifest_dir(root)?, verbose, debug)?;
+        let report = verify_files(entries, sample, verbose);
+        if !quiet { print_verify_report(&mut io::stdout(), &report)?; }
+        Ok(!report.corrupted.is_empty())
+    } else if let Some(command) = args.subcommand_matches(DUPES) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let dir = store.manifest_dir(root)?;
+        let (_, entries) = select_revision(&dir, load_entries(&dir, verbose, debug)?, command.value_of("rev").unwrap())?;
+        let groups = dupes::duplicate_groups(entries)?;
+        if !quiet { dupes::print_dupes(&mut io::stdout(), &groups, root, command.value_of("format") == Some("json"))?; }
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(OVERLAP) {
+        let first: &Path = &dir_arg(command, "first", &store)?;
+        let second: &Path = &dir_arg(command, "second", &store)?;
+        let overlap = overlap::overlap(load_latest_entries(&store.manifest_dir(first)?, verbose, debug)?,
+                                       load_latest_entries(&store.manifest_dir(second)?, verbose, debug)?)?;
+        if !quiet { overlap::print_overlap(&mut io::stdout(), &overlap, first, second)?; }
+        Ok(overlap.has_unique())
+    } else if let Some(command) = args.subcommand_matches(FIND) {
+        let query = match (command.value_of("name"), command.value_of("path"), command.value_of("hash")) {
+            (Some(name), _, _) => find::Query::name(name)?,
+            (_, Some(path), _) => find::Query::path(path)?,
+            (_, _, hash) => find::Query::hash(hash.unwrap())
+        };
+        let trees = match command.value_of_os("directory") {
+            Some(arg) => {
+                let root = dir_arg(command, "directory", &store)?;
+                if existing_format(&store.manifest_dir(&root)?).is_none() {
+                    return Err(DirdiffError::NoManifest(root).into());
+                }
+                vec![Tree { name: arg.to_string_lossy().into_owned(), path: encode_os_str(root.as_os_str()) }]
+            }
+            None => load_catalog(&store)?.trees
+        };
+        let mut out: Box<dyn Write> = if quiet { Box::new(io::sink()) } else { Box::new(io::stdout()) };
+        let found = find::find(&mut out, &trees, &store, &query, verbose, quiet)?;
+        if found == 0 && !quiet {
+            eprintln!("No matching files in any revision");
+        }
+        // No match is reported like grep does
+        Ok(found == 0)
+    } else if let Some(command) = args.subcommand_matches(BLAME) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let dir = store.manifest_dir(root)?;
+        let target = command.value_of("file").unwrap();
+        let entries = load_entries(&dir, verbose, debug)?;
+        let first = blame::first_appearance(&entries, target, root)
+            .ok_or_else(|| format!("No revision of {} contains {}", root.display(), target))?;
+        let events = blame::timeline(first, &pairwise_comparisons(command, entries, root, verbose, debug)?);
+        if !quiet { blame::print_timeline(&mut io::stdout(), &events, root, &load_revisions(&dir)?)?; }
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(PLAN) {
+        setup_plan(command, &store, verbose, debug, quiet)
+    } else if let Some(command) = args.subcommand_matches(APPLY) {
+        let plan = apply::load_plan(Path::new(command.value_of_os("plan").unwrap()))?;
+        let mut out: Box<dyn Write> = if quiet { Box::new(io::sink()) } else { Box::new(io::stdout()) };
+        apply::apply_plan(&mut out, &plan, !command.is_present("no-trash"), command.is_present("dry-run"), verbose)?;
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(LOG) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        let dir = store.manifest_dir(root)?;
+        let entries = load_entries(&dir, verbose, debug)?;
+        if !quiet { print_revisions(&mut io::stdout(), &entries, &load_revisions(&dir)?)?; }
+        Ok(false)
+    } else if let Some(command) = args.subcommand_matches(VERIFY_MANIFEST) {
+        let root: &Path = &dir_arg(command, "directory", &store)?;
+        Ok(fsck::verify_manifest(&store.manifest_dir(root)?, command.is_present("quarantine"), verbose, quiet)?)
     } else {
+        Ok(false)
     }
+}
 
-    Ok(())
+fn main() {
+    // Anything that still panics is an operational error, not a difference
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        exit(EXIT_ERROR);
+    }));
+
+    let result = run();
+    if let Err(error) = &result {
+        eprintln!("Error: {}", error);
+    }
+
+    exit(exit_code(&result));
+}
+
+fn exit_code(result: &Result<bool, Box<dyn Error>>) -> i32 {
+    match result {
+        Ok(false) => EXIT_NO_DIFFERENCES,
+        Ok(true) => EXIT_DIFFERENCES,
+        Err(_) => EXIT_ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{doc, ScratchDir};
+
+    #[test]
+    fn exit_codes_follow_the_comparison_outcome() {
+        assert_eq!(exit_code(&Ok(false)), 0);
+        assert_eq!(exit_code(&Ok(true)), 1);
+        assert_eq!(exit_code(&Err("unreadable manifest".into())), 2);
+    }
+
+    #[test]
+    fn quiet_comparisons_still_report_differences() {
+        let args = app().try_get_matches_from(vec!["dirdiff", "--quiet", "status", "/a"]).unwrap();
+        assert!(args.is_present("q"));
+        let command = args.subcommand_matches(STATUS).unwrap();
+
+        let prior = || vec![doc("/a", "kept.txt", "h1", 1, 1000)];
+        assert!(!compare_entries(command, prior(), vec![doc("/a", "kept.txt", "h1", 1, 2000)], &[Path::new("/a")],
+                                 false, false, true).unwrap());
+        assert!(compare_entries(command, prior(), vec![doc("/a", "new.txt", "h2", 1, 2000)], &[Path::new("/a")],
+                                false, false, true).unwrap());
+    }
+
+    fn compare(flags: &[&str], first: Vec<Doc>, second: Vec<Doc>) -> Comparison {
+        let args = app().try_get_matches_from([&["dirdiff", "status"], flags, &["/a"]].concat()).unwrap();
+        let options = match_options(args.subcommand_matches(STATUS).unwrap());
+
+        let mut conn = make_local_sqlite().unwrap();
+        create_dir_entries_table(&mut conn).unwrap();
+        load_to_local_sqlite(&mut conn, first, Path::new("/a"), &options).unwrap();
+        load_to_local_sqlite(&mut conn, second, Path::new("/a"), &options).unwrap();
+        history(&mut conn, false, false).unwrap()
+    }
+
+    #[test]
+    fn composed_and_decomposed_names_match_once_normalized() {
+        let prior = || vec![doc("/a", "caf\u{e9}.txt", "h1", 1, 1000)];
+        let latest = || vec![doc("/a", "cafe\u{301}.txt", "h1", 1, 2000)];
+
+        assert_eq!(compare(&[], prior(), latest()).renamed.len(), 1);
+        assert!(compare(&["--normalize-unicode", "nfc"], prior(), latest()).is_empty());
+        assert!(compare(&["--normalize-unicode", "nfd"], prior(), latest()).is_empty());
+    }
+
+    #[test]
+    fn names_differing_in_case_match_when_case_insensitive() {
+        let prior = || vec![doc("/a/Docs", "Readme.md", "h1", 1, 1000)];
+        let latest = || vec![doc("/a/docs", "README.md", "h1", 1, 2000)];
+
+        assert!(!compare(&[], prior(), latest()).is_empty());
+        assert!(compare(&["--case-insensitive"], prior(), latest()).is_empty());
+    }
+
+    #[test]
+    fn names_made_equal_by_the_match_options_are_reported_as_collisions() {
+        let prior = || vec![doc("/a", "a.txt", "h1", 1, 1000)];
+        let latest = || vec![doc("/a", "a.txt", "h1", 1, 2000), doc("/a", "A.txt", "h2", 1, 2000)];
+
+        assert!(compare(&[], prior(), latest()).collisions.is_empty());
+
+        let comparison = compare(&["--case-insensitive"], prior(), latest());
+        assert_eq!(comparison.collisions.len(), 1);
+        let mut names = comparison.collisions[0].iter().map(|d| d.name.as_str()).collect::<Vec<&str>>();
+        names.sort_unstable();
+        assert_eq!(names, vec!["A.txt", "a.txt"]);
+        assert!(!comparison.is_empty());
+    }
+
+    fn range(flags: &[&str], dir: &Path, entries: &[Doc]) -> Result<(u64, u64), Box<dyn Error>> {
+        let args = app().try_get_matches_from([&["dirdiff", "history", "/a"], flags].concat()).unwrap();
+        let (from, to) = history_range(args.subcommand_matches(HISTORY).unwrap(), dir, entries)?;
+        Ok((millis(from), millis(to)))
+    }
+
+    #[test]
+    fn history_ranges_default_to_the_last_two_revisions() {
+        let dir = ScratchDir::new("history-range");
+        let entries = [1000, 2000, 3000, 4000].iter().map(|d| doc("/a", "a", "h1", 1, *d)).collect::<Vec<Doc>>();
+
+        let mut revisions = load_revisions(&dir).unwrap();
+        revisions.add_tag(from_millis(2000), "start").unwrap();
+        revisions.save().unwrap();
+
+        assert_eq!(range(&[], &dir, &entries).unwrap(), (3000, 4000));
+        assert_eq!(range(&["--from", "start"], &dir, &entries).unwrap(), (2000, 4000));
+        assert_eq!(range(&["--to", "3000"], &dir, &entries).unwrap(), (2000, 3000));
+        assert_eq!(range(&["--range", "1000..start"], &dir, &entries).unwrap(), (1000, 2000));
+        assert_eq!(range(&["--range", "start.."], &dir, &entries).unwrap(), (2000, 4000));
+        assert_eq!(range(&["--range", "..3000"], &dir, &entries).unwrap(), (2000, 3000));
+
+        assert!(range(&["--range", "start"], &dir, &entries).is_err());
+        assert!(range(&["--from", "latest"], &dir, &entries).is_err());
+        assert!(range(&["--to", "1000"], &dir, &entries).is_err());
+        assert!(range(&[], &dir, &entries[..1]).is_err());
+    }
+
+    #[test]
+    fn transient_files_are_in_neither_end_of_the_range() {
+        let entries = vec![
+            doc("/a", "kept", "h1", 1, 1000),
+            doc("/a", "draft", "h2", 1, 2000),
+            doc("/a", "kept", "h1", 1, 2000),
+            doc("/a", "draft", "h2", 1, 3000),
+            doc("/a/old", "draft", "h2", 1, 3000),
+            doc("/a", "renamed-later", "h3", 1, 3000),
+            doc("/a", "scratch", "h4", 1, 5000),
+            doc("/a", "final", "h3", 1, 4000),
+        ];
+        let (from, to) = (from_millis(1000), from_millis(4000));
+
+        let transient = transient_files(&entries, from, to).into_iter()
+            .map(|d| relative_path(&d.full_path(), &[Path::new("/a")]))
+            .collect::<Vec<String>>();
+        assert_eq!(transient, vec!["draft", "old/draft"]);
+    }
 }
 
 
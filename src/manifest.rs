@@ -0,0 +1,225 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::atomic::{commit_temp_file, lock_manifest};
+use crate::binary::{read_binary_entries, read_binary_manifest, write_binary_entries, BINARY_MAGIC};
+use crate::dir_csv::*;
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::revisions::load_revisions;
+
+// Functions here take the directory holding the manifests, which is either the tracked root
+// itself or the root's directory in a central store, see `store.rs`
+pub const BINARY_MANIFEST_NAME: &str = ".dirdiff.bin";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ManifestFormat {
+    Csv,
+    Binary
+}
+
+impl FromStr for ManifestFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ManifestFormat::Csv),
+            "binary" => Ok(ManifestFormat::Binary),
+            _ => Err(format!("Unknown manifest format {}", s))
+        }
+    }
+}
+
+fn manifest_name(format: ManifestFormat) -> &'static str {
+    match format {
+        ManifestFormat::Csv => MANIFEST_NAME,
+        ManifestFormat::Binary => BINARY_MANIFEST_NAME
+    }
+}
+
+// The format of the manifest stored in `dir`, if there is one
+pub fn existing_format(dir: &Path) -> Option<ManifestFormat> {
+    if dir.join(BINARY_MANIFEST_NAME).exists() {
+        Some(ManifestFormat::Binary)
+    } else if dir.join(MANIFEST_NAME).exists() {
+        Some(ManifestFormat::Csv)
+    } else {
+        None
+    }
+}
+
+fn load_binary_entries(dir: &Path, verbose: bool, debug: bool) -> Result<Vec<Doc>> {
+    let path = dir.join(BINARY_MANIFEST_NAME);
+
+    if verbose { println!("Opening binary manifest {}", &path.display()); }
+
+    let file = File::open(&path).map_err(|e| DirdiffError::io(&path, e))?;
+    let entries = read_binary_entries(&path, &mut BufReader::new(file))?;
+
+    if debug { println!("Loaded {} binary manifest records", entries.len()); }
+
+    Ok(entries)
+}
+
+// Only the entries of the most recent revision
+fn latest_revision(entries: Vec<Doc>) -> Result<Vec<Doc>> {
+    let latest = entries.iter()
+        .map(|e| e.mod_date)
+        .max()
+        .ok_or(DirdiffError::NotEnoughRevisions { found: 0, needed: 1 })?;
+
+    Ok(entries.into_iter().filter(|e| e.mod_date == latest).collect())
+}
+
+// Every revision recorded for `dir`, whichever format its manifest is in
+pub fn load_entries(dir: &Path, verbose: bool, debug: bool) -> Result<Vec<Doc>> {
+    match existing_format(dir) {
+        Some(ManifestFormat::Binary) => load_binary_entries(dir, verbose, debug),
+        _ => load_csv_entries(create_csv_reader(dir, verbose)?, verbose, debug)
+    }
+}
+
+pub fn load_latest_entries(dir: &Path, verbose: bool, debug: bool) -> Result<Vec<Doc>> {
+    match existing_format(dir) {
+        Some(ManifestFormat::Binary) => latest_revision(load_binary_entries(dir, verbose, debug)?),
+        _ => load_csv_latest_entries(create_csv_reader(dir, verbose)?, verbose, debug)
+    }
+}
+
+// Single-quote a string for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Fetch a remote manifest over SSH, preferring the binary one like local reads do
+pub fn load_remote_latest_entries(host: &str, dir: &Path, verbose: bool, debug: bool) -> Result<Vec<Doc>> {
+    let binary_path = dir.join(BINARY_MANIFEST_NAME);
+    let csv_path = dir.join(MANIFEST_NAME);
+    let label = PathBuf::from(format!("{}:{}", host, dir.display()));
+
+    if verbose { println!("Fetching manifest {}", &label.display()); }
+
+    let remote_error = |message: String| DirdiffError::Remote {
+        host: host.to_string(),
+        path: dir.to_path_buf(),
+        message
+    };
+
+    let binary_quoted = shell_quote(&binary_path.to_string_lossy());
+    let output = Command::new("ssh")
+        .arg(host)
+        .arg(format!("if [ -e {} ]; then cat {}; else cat {}; fi",
+                     binary_quoted, binary_quoted, shell_quote(&csv_path.to_string_lossy())))
+        .output()
+        .map_err(|e| remote_error(format!("could not run ssh: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(remote_error(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    if output.stdout.starts_with(BINARY_MAGIC) {
+        latest_revision(read_binary_entries(&label, &mut &output.stdout[..])?)
+    } else {
+        load_csv_latest_entries(create_csv_reader_from_bytes(label, output.stdout)?, verbose, debug)
+    }
+}
+
+// Add revisions to the binary manifest of `dir` as a segment of their own. The compressed history
+// is copied over as it is rather than compressed again, unless the manifest has to be rewritten.
+fn append_binary_entries(dir: &Path, entries: Vec<Doc>, verbose: bool) -> Result<()> {
+    let path = dir.join(BINARY_MANIFEST_NAME);
+    let temp_path = dir.join(format!("{}.tmp", BINARY_MANIFEST_NAME));
+
+    let existing = fs::read(&path).map_err(|e| DirdiffError::io(&path, e))?;
+    let (mut all_entries, mut state) = read_binary_manifest(&path, &mut &existing[..])?;
+
+    if !state.can_append(&entries) {
+        all_entries.extend(entries);
+        return rewrite_manifest(dir, all_entries, ManifestFormat::Binary, verbose);
+    }
+
+    if verbose { println!("Appending to binary manifest {}", &path.display()); }
+
+    let file = File::create(&temp_path).map_err(|e| DirdiffError::io(&temp_path, e))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&existing)
+        .and_then(|_| state.write_segment(&mut writer, entries))
+        .and_then(|_| writer.flush())
+        .map_err(|e| DirdiffError::io(&temp_path, e))?;
+    let file = writer.into_inner().map_err(|e| DirdiffError::io(&temp_path, e.into_error()))?;
+
+    commit_temp_file(file, &temp_path, &path)
+}
+
+// Replace the whole manifest of `dir` with `entries` in the given format, removing a manifest
+// in the other format once the new one is in place. The caller must hold the manifest lock.
+pub fn rewrite_manifest(dir: &Path, entries: Vec<Doc>, format: ManifestFormat, verbose: bool) -> Result<()> {
+    match format {
+        ManifestFormat::Csv => rewrite_csv_entries(dir, entries, Vec::new(), verbose)?,
+        ManifestFormat::Binary => {
+            let path = dir.join(BINARY_MANIFEST_NAME);
+            let temp_path = dir.join(format!("{}.tmp", BINARY_MANIFEST_NAME));
+
+            if verbose { println!("Writing binary manifest {}", &path.display()); }
+
+            let file = File::create(&temp_path).map_err(|e| DirdiffError::io(&temp_path, e))?;
+            let mut writer = BufWriter::new(file);
+            write_binary_entries(&mut writer, entries)
+                .and_then(|_| writer.flush())
+                .map_err(|e| DirdiffError::io(&temp_path, e))?;
+            let file = writer.into_inner().map_err(|e| DirdiffError::io(&temp_path, e.into_error()))?;
+
+            commit_temp_file(file, &temp_path, &path)?;
+        }
+    }
+
+    for other in &[ManifestFormat::Csv, ManifestFormat::Binary] {
+        if *other != format {
+            let other_path = dir.join(manifest_name(*other));
+            match fs::remove_file(&other_path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound =>
+                    return Err(DirdiffError::io(&other_path, error)),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Add a revision to the manifest of `dir`, switching it to `format` if it is in another one
+pub fn write_revision(dir: &Path, entries: Vec<Doc>, format: ManifestFormat, message: Option<&str>, verbose: bool)
+                      -> Result<()> {
+    let _lock = lock_manifest(dir, verbose)?;
+    let revision = entries.first().map(|e| e.mod_date);
+
+    match (existing_format(dir), format) {
+        (None, ManifestFormat::Csv) | (Some(ManifestFormat::Csv), ManifestFormat::Csv) =>
+            write_csv_entries(dir, entries, verbose)?,
+        (None, ManifestFormat::Binary) => rewrite_manifest(dir, entries, format, verbose)?,
+        (Some(ManifestFormat::Binary), ManifestFormat::Binary) => append_binary_entries(dir, entries, verbose)?,
+        (Some(_), _) => {
+            let mut all_entries = load_entries(dir, verbose, false)?;
+            all_entries.extend(entries);
+            rewrite_manifest(dir, all_entries, format, verbose)?;
+        }
+    }
+
+    if let (Some(revision), Some(message)) = (revision, message) {
+        let mut revisions = load_revisions(dir)?;
+        revisions.set_message(revision, message);
+        revisions.save()?;
+    }
+
+    Ok(())
+}
+
+// Losslessly convert the manifest of `dir` to another format
+pub fn convert_manifest(dir: &Path, format: ManifestFormat, verbose: bool) -> Result<()> {
+    let _lock = lock_manifest(dir, verbose)?;
+    let entries = load_entries(dir, verbose, false)?;
+    rewrite_manifest(dir, entries, format, verbose)
+}
@@ -0,0 +1,146 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+// Names that are not valid UTF-8 are stored as this marker followed by their percent-escaped
+// bytes. Valid names that happen to start with the marker are escaped too, so decoding is exact.
+const ESCAPED_MARKER: &str = "%%";
+
+fn needs_escaping(byte: u8) -> bool {
+    byte == b'%' || !(0x20..0x7f).contains(&byte)
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::from(ESCAPED_MARKER);
+
+    for &byte in bytes {
+        if needs_escaping(byte) {
+            escaped.push_str(&format!("%{:02X}", byte));
+        } else {
+            escaped.push(byte as char);
+        }
+    }
+
+    escaped
+}
+
+fn unescape_bytes(escaped: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = escaped.bytes();
+
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let high = (chars.next()? as char).to_digit(16)?;
+            let low = (chars.next()? as char).to_digit(16)?;
+            bytes.push((high * 16 + low) as u8);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(unix)]
+fn os_str_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(name: &OsStr) -> Vec<u8> {
+    name.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn bytes_os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn bytes_os_string(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Encode a file name or path for storage in a manifest, without losing any bytes
+pub fn encode_os_str(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(name) if !name.starts_with(ESCAPED_MARKER) => name.to_string(),
+        _ => escape_bytes(&os_str_bytes(name))
+    }
+}
+
+// Inverse of `encode_os_str`. Malformed escapes are kept verbatim.
+pub fn decode_os_str(name: &str) -> OsString {
+    if let Some(escaped) = name.strip_prefix(ESCAPED_MARKER) {
+        if let Some(bytes) = unescape_bytes(escaped) {
+            return bytes_os_string(bytes);
+        }
+    }
+
+    OsString::from(name)
+}
+
+// The raw bytes of an encoded name or path, for output that must name the file exactly
+pub fn decode_bytes(name: &str) -> Vec<u8> {
+    os_str_bytes(&decode_os_str(name))
+}
+
+// Join an encoded directory and an encoded name into a single encoded path
+pub fn join_encoded(dir: &str, name: &str) -> String {
+    let path = Path::new(&decode_os_str(dir)).join(decode_os_str(name));
+    encode_os_str(path.as_os_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(name: &OsStr) -> String {
+        let encoded = encode_os_str(name);
+        assert_eq!(decode_os_str(&encoded), name, "{} did not round-trip", encoded);
+        encoded
+    }
+
+    #[test]
+    fn plain_names_are_kept_as_they_are() {
+        assert_eq!(round_trip(OsStr::new("")), "");
+        assert_eq!(round_trip(OsStr::new("report.pdf")), "report.pdf");
+        assert_eq!(round_trip(OsStr::new("100%")), "100%");
+        assert_eq!(round_trip(OsStr::new("%41")), "%41");
+        assert_eq!(round_trip(OsStr::new("naïve")), "naïve");
+    }
+
+    #[test]
+    fn names_starting_with_the_marker_are_escaped() {
+        assert_eq!(round_trip(OsStr::new("%%")), "%%%25%25");
+        assert_eq!(round_trip(OsStr::new("%%41")), "%%%25%2541");
+        assert_eq!(round_trip(OsStr::new("%%%")), "%%%25%25%25");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9 %\n");
+        assert_eq!(round_trip(name), "%%caf%E9 %25%0A");
+        assert_eq!(decode_bytes("%%caf%E9 %25%0A"), b"caf\xe9 %\n");
+        assert_eq!(round_trip(OsStr::from_bytes(b"\xff")), "%%%FF");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept_verbatim() {
+        assert_eq!(decode_os_str("%%a%zz"), OsStr::new("%%a%zz"));
+        assert_eq!(decode_os_str("%%%4"), OsStr::new("%%%4"));
+        assert_eq!(decode_os_str("%%"), OsStr::new(""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn joins_escaped_directories_and_names() {
+        assert_eq!(join_encoded("dir", "a"), "dir/a");
+        assert_eq!(join_encoded("", "a"), "a");
+        assert_eq!(join_encoded("%%d%FF", "%%%25%25"), "%%d%FF/%25%25");
+    }
+}
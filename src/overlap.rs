@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{create_dir_entries_table, get_doclist_from_table, load_to_local_sqlite, load_working_table,
+                make_local_sqlite, missing_files, touch_shared, setup_working_tables, MatchOptions};
+use crate::docs::Doc;
+use crate::error::Result;
+use crate::report::{human_bytes, relative_path, total_size};
+
+// How the contents of two trees relate, regardless of where files are in either
+pub struct Overlap {
+    // Files of the first tree whose contents are somewhere in the second
+    pub first_shared: Vec<Doc>,
+    pub first_unique: Vec<Doc>,
+    pub second_shared: Vec<Doc>,
+    pub second_unique: Vec<Doc>
+}
+
+impl Overlap {
+    pub fn has_unique(&self) -> bool {
+        !self.first_unique.is_empty() || !self.second_unique.is_empty()
+    }
+}
+
+fn revision_date(entries: &[Doc]) -> SystemTime {
+    entries.first().map(|e| e.mod_date).unwrap_or(UNIX_EPOCH)
+}
+
+fn to_datetime(date: SystemTime) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(date.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64, 0)
+}
+
+// Files of one side, in path order
+fn side_files(docs: &[Doc], date: &NaiveDateTime) -> Vec<Doc> {
+    let mut side = docs.iter().filter(|d| to_datetime(d.mod_date) == *date).cloned().collect::<Vec<Doc>>();
+    side.sort_by(|a, b| (&a.path, &a.name).cmp(&(&b.path, &b.name)));
+    side
+}
+
+// Match the latest revisions of two trees on hash, with the working tables history uses
+pub fn overlap(first_entries: Vec<Doc>, mut second_entries: Vec<Doc>) -> Result<Overlap> {
+    let first_date = revision_date(&first_entries);
+
+    // Both sides are told apart by revision date, which only has the precision of a second
+    // in the database
+    if to_datetime(revision_date(&second_entries)) == to_datetime(first_date) {
+        for entry in second_entries.iter_mut() {
+            entry.mod_date = first_date + Duration::from_secs(1);
+        }
+    }
+    let second_date = revision_date(&second_entries);
+
+    let mut conn = make_local_sqlite()?;
+    create_dir_entries_table(&mut conn)?;
+    // Only contents are matched here, so paths need not be made relative
+    load_to_local_sqlite(&mut conn, first_entries, Path::new(""), &MatchOptions::default())?;
+    load_to_local_sqlite(&mut conn, second_entries, Path::new(""), &MatchOptions::default())?;
+
+    let (first, second) = (to_datetime(first_date), to_datetime(second_date));
+    setup_working_tables(&mut conn)?;
+    load_working_table(&second, &first, &conn)?;
+    touch_shared(&first, &second, &mut conn)?;
+    touch_shared(&second, &first, &mut conn)?;
+
+    let shared = get_doclist_from_table("touched_entries", &mut conn)?;
+
+    Ok(Overlap {
+        first_shared: side_files(&shared, &first),
+        first_unique: side_files(&missing_files(&first, &conn)?, &first),
+        second_shared: side_files(&shared, &second),
+        second_unique: side_files(&missing_files(&second, &conn)?, &second)
+    })
+}
+
+// Bytes to copy over for the other side to hold every one of `unique`, each contents once
+pub fn absorb_bytes(unique: &[Doc]) -> u64 {
+    let mut seen = HashSet::new();
+    total_size(&mut unique.iter().filter(|d| seen.insert(&d.hash)))
+}
+
+fn print_section(out: &mut dyn Write, title: &str, docs: &[Doc], root: &Path) -> std::io::Result<()> {
+    writeln!(out, "{} ({} files, {}):", title, docs.len(), human_bytes(total_size(&mut docs.iter())))?;
+    for doc in docs {
+        writeln!(out, "  {}", relative_path(&doc.full_path(), &[root]))?;
+    }
+
+    Ok(())
+}
+
+pub fn print_overlap(out: &mut dyn Write, overlap: &Overlap, first: &Path, second: &Path)
+                     -> std::result::Result<(), Box<dyn Error>> {
+    print_section(out, &format!("In {} and somewhere in {}", first.display(), second.display()),
+                  &overlap.first_shared, first)?;
+    print_section(out, &format!("Only in {}", first.display()), &overlap.first_unique, first)?;
+    print_section(out, &format!("In {} and somewhere in {}", second.display(), first.display()),
+                  &overlap.second_shared, second)?;
+    print_section(out, &format!("Only in {}", second.display()), &overlap.second_unique, second)?;
+
+    writeln!(out, "{} needs {} to absorb {}", second.display(), human_bytes(absorb_bytes(&overlap.first_unique)),
+             first.display())?;
+    writeln!(out, "{} needs {} to absorb {}", first.display(), human_bytes(absorb_bytes(&overlap.second_unique)),
+             second.display())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::doc;
+
+    fn names(docs: &[Doc]) -> Vec<&str> {
+        docs.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    #[test]
+    fn files_are_matched_on_contents_wherever_they_are() {
+        let first = vec![doc("/a", "shared", "h1", 10, 1000), doc("/a", "mine", "h2", 20, 1000),
+                         doc("/a/sub", "mine-again", "h2", 20, 1000)];
+        let second = vec![doc("/b/elsewhere", "renamed", "h1", 10, 1000), doc("/b", "theirs", "h3", 300, 1000)];
+
+        let overlap = overlap(first, second).unwrap();
+
+        assert_eq!(names(&overlap.first_shared), vec!["shared"]);
+        assert_eq!(names(&overlap.first_unique), vec!["mine", "mine-again"]);
+        assert_eq!(names(&overlap.second_shared), vec!["renamed"]);
+        assert_eq!(names(&overlap.second_unique), vec!["theirs"]);
+        assert!(overlap.has_unique());
+    }
+
+    #[test]
+    fn absorbing_copies_each_contents_once() {
+        let unique = vec![doc("/a", "x", "h1", 20, 1000), doc("/a/sub", "x", "h1", 20, 1000),
+                          doc("/a", "y", "h2", 300, 1000)];
+
+        assert_eq!(absorb_bytes(&unique), 320);
+        assert_eq!(absorb_bytes(&[]), 0);
+
+        let mut out = Vec::new();
+        let overlap = overlap(unique, vec![doc("/b", "z", "h2", 300, 2000)]).unwrap();
+        print_overlap(&mut out, &overlap, Path::new("/a"), Path::new("/b")).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Only in /a (2 files, 40 B):"), "{}", text);
+        assert!(text.contains("/b needs 20 B to absorb /a"), "{}", text);
+        assert!(text.contains("/a needs 0 B to absorb /b"), "{}", text);
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::names::{decode_bytes, decode_os_str, encode_os_str, join_encoded};
+use crate::report::{human_bytes, Comparison};
+
+// Suffix of the temporary name a file is parked under when moves go round in a cycle
+const PARKED_SUFFIX: &str = ".dirdiff-tmp";
+
+// What it takes to turn the target directory into a copy of the source directory
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    // Canonical roots, encoded like manifest paths
+    pub source: String,
+    pub target: String,
+    pub operations: Vec<Operation>
+}
+
+// A step of a plan, in the order they are to be carried out. Paths are relative to the target,
+// except for transfers, which copy a file from the same path in the source.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Operation {
+    Mkdir { path: String },
+    Move { from: String, to: String, hash: String },
+    Rename { from: String, to: String, hash: String },
+    // Copy a file already in the target rather than transferring it again
+    CopyLocalDuplicate { from: String, to: String, hash: String, size: Option<u64> },
+    Transfer { path: String, hash: String, size: Option<u64> },
+    Delete { path: String, hash: String }
+}
+
+fn parent(path: &str) -> PathBuf {
+    Path::new(&decode_os_str(path)).parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+// Same directory means a rename, which changes nothing but the name
+fn move_operation(from: String, to: String, hash: String) -> Operation {
+    if parent(&from) == parent(&to) {
+        Operation::Rename { from, to, hash }
+    } else {
+        Operation::Move { from, to, hash }
+    }
+}
+
+// Make the entries of the latest revision of `root` relative to it, and give them `revision` as
+// date so that two trees can be compared as revisions of the same one
+pub fn relative_entries(entries: Vec<Doc>, root: &Path, revision: SystemTime) -> Result<Vec<Doc>> {
+    let canonical = root.canonicalize().map_err(|e| DirdiffError::io(root, e))?;
+
+    entries.into_iter().map(|mut entry| {
+        let full_path = PathBuf::from(decode_os_str(&entry.full_path()));
+        let relative = full_path.strip_prefix(root)
+            .or_else(|_| full_path.strip_prefix(&canonical))
+            .map_err(|_| DirdiffError::invalid_path(&full_path, &format!(
+                "recorded outside {}, record the directory again", root.display())))?;
+
+        entry.path = encode_os_str(relative.parent().unwrap_or_else(|| Path::new("")).as_os_str());
+        entry.mod_date = revision;
+        Ok(entry)
+    }).collect()
+}
+
+struct PendingMove {
+    from: String,
+    to: String,
+    hash: String
+}
+
+// A name to park `path` under that is not `taken` by a file of either tree, nor by a file parked
+// before it. The first choice is `path` with PARKED_SUFFIX, then a counter is added.
+fn parked_name(path: &str, taken: &mut HashSet<String>) -> String {
+    let mut name = format!("{}{}", path, PARKED_SUFFIX);
+    let mut counter = 1;
+
+    while taken.contains(&name) {
+        name = format!("{}{}-{}", path, PARKED_SUFFIX, counter);
+        counter += 1;
+    }
+
+    taken.insert(name.clone());
+    name
+}
+
+// Order moves so none lands on a file yet to be moved away. Files whose moves wait on each other
+// in a cycle are parked under a temporary name first.
+fn order_moves(mut pending: Vec<PendingMove>, taken: &mut HashSet<String>, operations: &mut Vec<Operation>) {
+    // A move waits for the one moving away the file at its destination, and is ready once that is done
+    let by_destination = pending.iter().enumerate()
+        .map(|(i, m)| (m.to.clone(), i))
+        .collect::<HashMap<String, usize>>();
+    let sources = pending.iter().map(|m| m.from.as_str()).collect::<HashSet<&str>>();
+    let mut ready = (0..pending.len()).filter(|i| !sources.contains(pending[*i].to.as_str())).collect::<VecDeque<usize>>();
+    let mut done = vec![false; pending.len()];
+    let mut next_unparked = 0;
+
+    loop {
+        while let Some(i) = ready.pop_front() {
+            let ready_move = &pending[i];
+            operations.push(move_operation(ready_move.from.clone(), ready_move.to.clone(), ready_move.hash.clone()));
+            done[i] = true;
+            if let Some(&waiting) = by_destination.get(&ready_move.from) {
+                ready.push_back(waiting);
+            }
+        }
+
+        // Whatever is left waits in cycles, so park the first one left to break its cycle
+        match (next_unparked..pending.len()).find(|i| !done[*i]) {
+            Some(i) => {
+                next_unparked = i + 1;
+                let parked = &mut pending[i];
+                let temp = parked_name(&parked.from, taken);
+                operations.push(move_operation(parked.from.clone(), temp.clone(), parked.hash.clone()));
+                let freed = std::mem::replace(&mut parked.from, temp);
+                if let Some(&waiting) = by_destination.get(&freed) {
+                    ready.push_back(waiting);
+                }
+            }
+            None => break
+        }
+    }
+}
+
+// Directories that must be created for `paths`, parents first
+fn missing_dirs<'a>(paths: impl Iterator<Item = &'a String>, target_entries: &[Doc]) -> Vec<String> {
+    let ancestors = |path: &str| parent(path).ancestors()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| encode_os_str(dir.as_os_str()))
+        .collect::<Vec<String>>();
+
+    let existing = target_entries.iter().flat_map(|e| ancestors(&e.full_path())).collect::<HashSet<String>>();
+
+    paths.flat_map(|path| ancestors(path))
+        .filter(|dir| !existing.contains(dir))
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+// Turn the comparison of the target (the older revision) with the source (the newer one) into
+// operations. Contents already in the target are moved, renamed or copied locally, and only
+// contents the target has nowhere are transferred.
+pub fn build_plan(comparison: Comparison, source_entries: &[Doc], target_entries: &[Doc],
+                  source: &Path, target: &Path) -> Result<Plan> {
+    let canonical = |root: &Path| root.canonicalize()
+        .map(|r| encode_os_str(r.as_os_str()))
+        .map_err(|e| DirdiffError::io(root, e));
+
+    let mut moves = Vec::new();
+    for renamed in comparison.renamed {
+        moves.push(PendingMove {
+            from: join_encoded(&renamed.doc.path, &renamed.prior_name),
+            to: renamed.doc.full_path(),
+            hash: renamed.doc.hash
+        });
+    }
+    for moved in comparison.moved {
+        moves.push(PendingMove {
+            from: join_encoded(&moved.prior_path, &moved.prior_name),
+            to: moved.doc.full_path(),
+            hash: moved.doc.hash
+        });
+    }
+
+    // A file about to be deleted is better moved to where the same contents are wanted
+    let mut deletable: HashMap<&str, Vec<&Doc>> = HashMap::new();
+    for doc in &comparison.missing {
+        deletable.entry(doc.hash.as_str()).or_default().push(doc);
+    }
+
+    let mut added = comparison.added.iter().collect::<Vec<&Doc>>();
+    added.sort_by_key(|d| d.full_path());
+
+    let mut fresh = Vec::new();
+    let mut reused = HashSet::new();
+    for doc in added {
+        match deletable.get_mut(doc.hash.as_str()).and_then(|docs| docs.pop()) {
+            Some(missing) => {
+                reused.insert(missing.full_path());
+                moves.push(PendingMove { from: missing.full_path(), to: doc.full_path(), hash: doc.hash.clone() });
+            }
+            None => fresh.push(doc)
+        }
+    }
+
+    let destinations = moves.iter().map(|m| m.to.clone())
+        .chain(fresh.iter().map(|d| d.full_path()))
+        .collect::<Vec<String>>();
+    let dirs = missing_dirs(destinations.iter(), target_entries);
+
+    // Files in the way of a new file or directory go first, the rest once everything is in place
+    let in_the_way = destinations.iter().chain(dirs.iter()).collect::<HashSet<&String>>();
+    let (early_deletes, late_deletes): (Vec<&Doc>, Vec<&Doc>) = comparison.missing.iter()
+        .filter(|d| !reused.contains(&d.full_path()))
+        .partition(|d| in_the_way.contains(&d.full_path()));
+
+    let mut operations = early_deletes.iter()
+        .map(|d| Operation::Delete { path: d.full_path(), hash: d.hash.clone() })
+        .collect::<Vec<Operation>>();
+    operations.extend(dirs.into_iter().map(|path| Operation::Mkdir { path }));
+    let mut taken = source_entries.iter().chain(target_entries).map(Doc::full_path).collect::<HashSet<String>>();
+    order_moves(moves, &mut taken, &mut operations);
+
+    // Once moved, every file of the source that is not new is in place, and files still to be
+    // deleted can serve as copies until the end
+    let fresh_paths = fresh.iter().map(|d| d.full_path()).collect::<HashSet<String>>();
+    let mut local: HashMap<String, String> = HashMap::new();
+    for doc in late_deletes.iter().cloned().chain(source_entries.iter().filter(|d| !d.is_unreadable())) {
+        if !fresh_paths.contains(&doc.full_path()) {
+            local.insert(doc.hash.clone(), doc.full_path());
+        }
+    }
+
+    for doc in fresh {
+        match local.get(&doc.hash) {
+            Some(from) => operations.push(Operation::CopyLocalDuplicate {
+                from: from.clone(), to: doc.full_path(), hash: doc.hash.clone(), size: doc.size
+            }),
+            None => {
+                operations.push(Operation::Transfer { path: doc.full_path(), hash: doc.hash.clone(), size: doc.size });
+                local.insert(doc.hash.clone(), doc.full_path());
+            }
+        }
+    }
+
+    operations.extend(late_deletes.iter().map(|d| Operation::Delete { path: d.full_path(), hash: d.hash.clone() }));
+
+    Ok(Plan { source: canonical(source)?, target: canonical(target)?, operations })
+}
+
+impl Operation {
+    // One line in listings, paths being relative to the target
+    pub fn describe(&self) -> String {
+        match self {
+            Operation::Mkdir { path } => format!("mkdir     {}", path),
+            Operation::Move { from, to, .. } => format!("move      {} -> {}", from, to),
+            Operation::Rename { from, to, .. } => format!("rename    {} -> {}", from, to),
+            Operation::CopyLocalDuplicate { from, to, .. } => format!("copy      {} -> {}", from, to),
+            Operation::Transfer { path, .. } => format!("transfer  {}", path),
+            Operation::Delete { path, .. } => format!("delete    {}", path)
+        }
+    }
+}
+
+pub fn print_plan(out: &mut dyn Write, plan: &Plan, json: bool) -> std::result::Result<(), Box<dyn Error>> {
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(plan)?)?;
+        return Ok(());
+    }
+
+    let (mut copied, mut transferred) = (0, 0);
+    for operation in &plan.operations {
+        match operation {
+            Operation::CopyLocalDuplicate { size, .. } => copied += size.unwrap_or(0),
+            Operation::Transfer { size, .. } => transferred += size.unwrap_or(0),
+            _ => ()
+        }
+        writeln!(out, "{}", operation.describe())?;
+    }
+
+    writeln!(out, "{} operations, {} to transfer, {} copied within the target", plan.operations.len(),
+             human_bytes(transferred), human_bytes(copied))?;
+
+    Ok(())
+}
+
+// Quote a path for a POSIX shell, byte for byte
+fn shell_quote(path: &str) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for byte in decode_bytes(path) {
+        match byte {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(byte)
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+// The files to send from the source, which also includes local copies of files that are being
+// transferred themselves, as scripts cannot wait for the transfer to copy them
+fn transferred_paths(plan: &Plan) -> BTreeSet<&str> {
+    let mut transferred = BTreeSet::new();
+
+    for operation in &plan.operations {
+        match operation {
+            Operation::Transfer { path, .. } => { transferred.insert(path.as_str()); },
+            Operation::CopyLocalDuplicate { from, to, .. } if transferred.contains(from.as_str()) => {
+                transferred.insert(to.as_str());
+            },
+            _ => ()
+        }
+    }
+
+    transferred
+}
+
+// The plan as a POSIX shell script run in the target directory, or in the one given as its first
+// argument. It does everything but the transfers, which are left to `print_rsync_list`. Files
+// the transfers replace are not deleted, so running it after them cannot remove what they brought.
+pub fn print_shell_script(out: &mut dyn Write, plan: &Plan) -> io::Result<()> {
+    let transferred = transferred_paths(plan);
+
+    writeln!(out, "#!/bin/sh")?;
+    writeln!(out, "# Makes the target a copy of {}, together with the files listed by",
+             String::from_utf8_lossy(&decode_bytes(&plan.source)))?;
+    writeln!(out, "# `dirdiff plan --emit rsync`. Run it BEFORE `rsync --files-from` with that list, as it")?;
+    writeln!(out, "# moves files away from paths the transfers fill.")?;
+    writeln!(out, "set -e")?;
+    out.write_all(b"if [ $# -gt 0 ]; then cd -- \"$1\"; else cd -- ")?;
+    out.write_all(&shell_quote(&plan.target))?;
+    out.write_all(b"; fi\n")?;
+
+    for operation in &plan.operations {
+        let (command, paths): (&[u8], Vec<&String>) = match operation {
+            Operation::Mkdir { path } => (b"mkdir -p --", vec![path]),
+            Operation::Move { from, to, .. } | Operation::Rename { from, to, .. } => (b"mv --", vec![from, to]),
+            Operation::CopyLocalDuplicate { from, to, .. } if !transferred.contains(to.as_str()) =>
+                (b"cp -p --", vec![from, to]),
+            Operation::Delete { path, .. } if !transferred.contains(path.as_str()) => (b"rm --", vec![path]),
+            _ => continue
+        };
+
+        out.write_all(command)?;
+        for path in paths {
+            out.write_all(b" ")?;
+            out.write_all(&shell_quote(path))?;
+        }
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+// The files to transfer, one per line relative to the source, for `rsync --files-from`
+pub fn print_rsync_list(out: &mut dyn Write, plan: &Plan) -> Result<()> {
+    let write_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+
+    for path in transferred_paths(plan) {
+        let bytes = decode_bytes(path);
+        if bytes.contains(&b'\n') {
+            return Err(DirdiffError::invalid_path(Path::new(path), "cannot list a path with a newline for rsync"));
+        }
+
+        out.write_all(&bytes).and_then(|_| out.write_all(b"\n")).map_err(write_error)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(from: &str, to: &str) -> PendingMove {
+        PendingMove { from: from.to_string(), to: to.to_string(), hash: format!("hash-{}", from) }
+    }
+
+    fn ordered(moves: Vec<PendingMove>, taken: &[&str]) -> Vec<String> {
+        let mut taken = taken.iter().map(|t| t.to_string()).collect::<HashSet<String>>();
+        let mut operations = Vec::new();
+        order_moves(moves, &mut taken, &mut operations);
+
+        operations.iter()
+            .map(|operation| match operation {
+                Operation::Move { from, to, hash } => format!("move {} -> {} {}", from, to, hash),
+                Operation::Rename { from, to, hash } => format!("rename {} -> {} {}", from, to, hash),
+                _ => panic!("moves only produce moves and renames")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn moves_wait_for_their_destination_to_be_moved_away() {
+        assert_eq!(ordered(vec![pending("a", "b"), pending("b", "c"), pending("c", "dir/d")], &[]), vec![
+            "move c -> dir/d hash-c",
+            "rename b -> c hash-b",
+            "rename a -> b hash-a"
+        ]);
+    }
+
+    #[test]
+    fn cycles_are_broken_by_parking_a_file() {
+        let moves = vec![pending("a", "b"), pending("b", "c"), pending("c", "a"), pending("x", "a.new")];
+
+        assert_eq!(ordered(moves, &[]), vec![
+            "rename x -> a.new hash-x",
+            "rename a -> a.dirdiff-tmp hash-a",
+            "rename c -> a hash-c",
+            "rename b -> c hash-b",
+            "rename a.dirdiff-tmp -> b hash-a"
+        ]);
+    }
+
+    #[test]
+    fn parked_names_avoid_existing_files() {
+        let moves = vec![pending("a", "b"), pending("b", "a"), pending("dir/c", "dir/d"), pending("dir/d", "dir/c")];
+
+        assert_eq!(ordered(moves, &["a.dirdiff-tmp", "a.dirdiff-tmp-1", "dir/c.dirdiff-tmp-1"]), vec![
+            "rename a -> a.dirdiff-tmp-2 hash-a",
+            "rename b -> a hash-b",
+            "rename a.dirdiff-tmp-2 -> b hash-a",
+            "rename dir/c -> dir/c.dirdiff-tmp hash-dir/c",
+            "rename dir/d -> dir/c hash-dir/d",
+            "rename dir/c.dirdiff-tmp -> dir/d hash-dir/c"
+        ]);
+    }
+
+    #[test]
+    fn shell_quoting_is_byte_exact() {
+        assert_eq!(shell_quote("plain"), b"'plain'");
+        assert_eq!(shell_quote(""), b"''");
+        assert_eq!(shell_quote("it's"), b"'it'\\''s'");
+        assert_eq!(shell_quote("''"), b"''\\'''\\'''");
+        assert_eq!(shell_quote("line\nbreak $HOME `id` \\"), b"'line\nbreak $HOME `id` \\'");
+        assert_eq!(shell_quote("%%caf%E9"), b"'caf\xe9'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shells_read_quoted_paths_back_unchanged() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::process::Command;
+
+        for path in &["it's", "line\nbreak", "$(echo no) `id` \"\\\"", "'", " lead and trail ", "%%caf%E9%0A"] {
+            let script = [b"printf %s ".to_vec(), shell_quote(path)].concat();
+            let output = Command::new("sh").arg("-c").arg(OsStr::from_bytes(&script)).output().unwrap();
+            assert_eq!(output.stdout, decode_bytes(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn scripts_leave_transferred_paths_to_rsync() {
+        let plan = Plan {
+            source: "/source".to_string(),
+            target: "/target's".to_string(),
+            operations: vec![
+                Operation::Delete { path: "changed".to_string(), hash: "old".to_string() },
+                Operation::Mkdir { path: "new dir".to_string() },
+                Operation::Rename { from: "a".to_string(), to: "b".to_string(), hash: "ab".to_string() },
+                Operation::Transfer { path: "changed".to_string(), hash: "new".to_string(), size: Some(1) },
+                Operation::CopyLocalDuplicate { from: "changed".to_string(), to: "new dir/copy".to_string(),
+                                                hash: "new".to_string(), size: Some(1) },
+                Operation::CopyLocalDuplicate { from: "b".to_string(), to: "new dir/b".to_string(),
+                                                hash: "ab".to_string(), size: Some(1) },
+                Operation::Delete { path: "gone".to_string(), hash: "gone".to_string() }
+            ]
+        };
+
+        let mut script = Vec::new();
+        print_shell_script(&mut script, &plan).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let commands = script.lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>();
+        assert_eq!(commands, vec![
+            "set -e",
+            "if [ $# -gt 0 ]; then cd -- \"$1\"; else cd -- '/target'\\''s'; fi",
+            "mkdir -p -- 'new dir'",
+            "mv -- 'a' 'b'",
+            "cp -p -- 'b' 'new dir/b'",
+            "rm -- 'gone'"
+        ]);
+
+        let mut list = Vec::new();
+        print_rsync_list(&mut list, &plan).unwrap();
+        assert_eq!(list, b"changed\nnew dir/copy\n");
+    }
+
+    #[test]
+    fn rsync_lists_refuse_newlines() {
+        let plan = Plan {
+            source: "/source".to_string(),
+            target: "/target".to_string(),
+            operations: vec![Operation::Transfer { path: "line\nbreak".to_string(), hash: "h".to_string(), size: None }]
+        };
+
+        assert!(matches!(print_rsync_list(&mut Vec::new(), &plan), Err(DirdiffError::InvalidPath { .. })));
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Local};
+
+use crate::atomic::lock_manifest;
+use crate::dir_csv::MANIFEST_NAME;
+use crate::error::{DirdiffError, Result};
+use crate::manifest::*;
+use crate::report::human_bytes;
+use crate::revisions::{describe_revision, load_revisions};
+
+// How many revisions to keep, borg-style: the latest `last` revisions, then the latest revision
+// of each of the latest `daily` days that have one, and so on for longer periods. A revision
+// kept by any rule is kept, and so is every tagged revision.
+#[derive(Default)]
+pub struct RetentionPolicy {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0 && self.yearly == 0
+    }
+}
+
+// Keep the newest revision of each of the `count` newest periods, periods being told apart by `key`
+fn keep_per_period<K: PartialEq>(newest_first: &[SystemTime], count: usize, key: impl Fn(&DateTime<Local>) -> K,
+                                 kept: &mut BTreeSet<SystemTime>) {
+    let mut last_key = None;
+    let mut periods = 0;
+
+    for date in newest_first {
+        if periods == count {
+            break;
+        }
+
+        let period = key(&DateTime::<Local>::from(*date));
+        if last_key.as_ref() != Some(&period) {
+            kept.insert(*date);
+            last_key = Some(period);
+            periods += 1;
+        }
+    }
+}
+
+// The revisions `policy` keeps out of `revisions`
+pub fn select_revisions(revisions: &BTreeSet<SystemTime>, policy: &RetentionPolicy) -> BTreeSet<SystemTime> {
+    let newest_first = revisions.iter().rev().cloned().collect::<Vec<SystemTime>>();
+    let mut kept = newest_first.iter().take(policy.last).cloned().collect::<BTreeSet<SystemTime>>();
+
+    keep_per_period(&newest_first, policy.daily, |d| d.naive_local().date(), &mut kept);
+    keep_per_period(&newest_first, policy.weekly, |d| (d.iso_week().year(), d.iso_week().week()), &mut kept);
+    keep_per_period(&newest_first, policy.monthly, |d| (d.year(), d.month()), &mut kept);
+    keep_per_period(&newest_first, policy.yearly, |d| d.year(), &mut kept);
+
+    kept
+}
+
+fn manifest_size(dir: &Path) -> u64 {
+    [MANIFEST_NAME, BINARY_MANIFEST_NAME].iter()
+        .filter_map(|name| fs::metadata(dir.join(name)).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// The `prune` command: drop the revisions of the manifest in `dir` that `policy` does not keep
+pub fn prune(out: &mut dyn Write, dir: &Path, policy: &RetentionPolicy, dry_run: bool, verbose: bool)
+             -> Result<()> {
+    let write_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+
+    if policy.is_empty() {
+        return Err(DirdiffError::refused(dir, "no retention policy given, refusing to remove every revision"));
+    }
+
+    let _lock = lock_manifest(dir, verbose)?;
+    let format = existing_format(dir).unwrap_or(ManifestFormat::Csv);
+    let entries = load_entries(dir, verbose, false)?;
+
+    let mut revision_info = load_revisions(dir)?;
+
+    let revisions = entries.iter().map(|e| e.mod_date).collect::<BTreeSet<SystemTime>>();
+    let mut kept = select_revisions(&revisions, policy);
+    // Tagged revisions were singled out on purpose, so no policy removes them
+    kept.extend(revisions.iter().filter(|r| revision_info.is_tagged(**r)));
+    let removed = revisions.difference(&kept).collect::<Vec<&SystemTime>>();
+
+    for date in &removed {
+        if verbose || dry_run {
+            writeln!(out, "Removing revision {}", describe_revision(**date, &revision_info)).map_err(write_error)?;
+        }
+    }
+
+    if dry_run {
+        writeln!(out, "Would keep {} of {} revisions", kept.len(), revisions.len()).map_err(write_error)?;
+        return Ok(());
+    }
+
+    if removed.is_empty() {
+        writeln!(out, "Kept all {} revisions, nothing to prune", revisions.len()).map_err(write_error)?;
+        return Ok(());
+    }
+
+    let size_before = manifest_size(dir);
+    let kept_entries = entries.into_iter().filter(|e| kept.contains(&e.mod_date)).collect();
+    rewrite_manifest(dir, kept_entries, format, verbose)?;
+    revision_info.retain(&kept);
+    revision_info.save()?;
+    let size_after = manifest_size(dir);
+
+    writeln!(out, "Kept {} of {} revisions, removed {}; manifest {} -> {}, reclaimed {}",
+             kept.len(), revisions.len(), removed.len(),
+             human_bytes(size_before), human_bytes(size_after),
+             human_bytes(size_before.saturating_sub(size_after))).map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> SystemTime {
+        SystemTime::from(Local.ymd(year, month, day).and_hms(hour, min, sec))
+    }
+
+    fn select(revisions: &[SystemTime], policy: RetentionPolicy) -> Vec<SystemTime> {
+        select_revisions(&revisions.iter().cloned().collect(), &policy).into_iter().collect()
+    }
+
+    #[test]
+    fn daily_keeps_the_last_revision_before_midnight() {
+        let revisions = [at(2020, 7, 12, 10, 0, 0), at(2020, 7, 12, 23, 59, 59), at(2020, 7, 13, 0, 0, 0),
+                         at(2020, 7, 13, 8, 0, 0)];
+
+        assert_eq!(select(&revisions, RetentionPolicy { daily: 1, ..RetentionPolicy::default() }),
+                   vec![revisions[3]]);
+        assert_eq!(select(&revisions, RetentionPolicy { daily: 2, ..RetentionPolicy::default() }),
+                   vec![revisions[1], revisions[3]]);
+    }
+
+    #[test]
+    fn days_without_revisions_do_not_count() {
+        let revisions = [at(2020, 7, 1, 12, 0, 0), at(2020, 7, 10, 12, 0, 0)];
+
+        assert_eq!(select(&revisions, RetentionPolicy { daily: 2, ..RetentionPolicy::default() }),
+                   revisions.to_vec());
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // 2020-07-12 is a Sunday
+        let revisions = [at(2020, 7, 6, 0, 0, 0), at(2020, 7, 12, 23, 59, 59), at(2020, 7, 13, 0, 0, 0)];
+
+        assert_eq!(select(&revisions, RetentionPolicy { weekly: 1, ..RetentionPolicy::default() }),
+                   vec![revisions[2]]);
+        assert_eq!(select(&revisions, RetentionPolicy { weekly: 2, ..RetentionPolicy::default() }),
+                   vec![revisions[1], revisions[2]]);
+    }
+
+    #[test]
+    fn iso_weeks_span_the_new_year() {
+        // 2020-12-31 and 2021-01-03 are both in week 53 of 2020
+        let revisions = [at(2020, 12, 31, 12, 0, 0), at(2021, 1, 3, 12, 0, 0), at(2021, 1, 4, 12, 0, 0)];
+
+        assert_eq!(select(&revisions, RetentionPolicy { weekly: 2, ..RetentionPolicy::default() }),
+                   vec![revisions[1], revisions[2]]);
+        assert_eq!(select(&revisions, RetentionPolicy { monthly: 2, ..RetentionPolicy::default() }),
+                   vec![revisions[0], revisions[2]]);
+        assert_eq!(select(&revisions, RetentionPolicy { yearly: 1, ..RetentionPolicy::default() }),
+                   vec![revisions[2]]);
+    }
+
+    #[test]
+    fn rules_add_up() {
+        let revisions = [at(2020, 7, 1, 12, 0, 0), at(2020, 7, 13, 8, 0, 0), at(2020, 7, 13, 9, 0, 0),
+                         at(2020, 7, 13, 10, 0, 0)];
+
+        assert_eq!(select(&revisions, RetentionPolicy { last: 2, daily: 2, ..RetentionPolicy::default() }),
+                   vec![revisions[0], revisions[2], revisions[3]]);
+        assert!(RetentionPolicy::default().is_empty());
+        assert!(select(&revisions, RetentionPolicy::default()).is_empty());
+    }
+}
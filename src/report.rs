@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::docs::*;
+use crate::html::print_html;
+use crate::names::{decode_os_str, encode_os_str, join_encoded};
+
+pub enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
+    Tree,
+    Html
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "tree" => Ok(OutputFormat::Tree),
+            "html" => Ok(OutputFormat::Html),
+            _ => Err(format!("Unknown output format {}", s))
+        }
+    }
+}
+
+// Result of comparing two revisions, in the order the working table is whittled down
+pub struct Comparison {
+    pub renamed: Vec<RenamedDoc>,
+    pub moved: Vec<MovedDoc>,
+    pub missing: Vec<Doc>,
+    pub added: Vec<Doc>,
+    // Files that came and went between two distant revisions, so neither of them has their contents
+    pub transient: Vec<Doc>,
+    // Files of the same revision that only the match options made indistinguishable, by group
+    pub collisions: Vec<Vec<Doc>>
+}
+
+#[derive(PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeType {
+    Renamed,
+    Moved,
+    Missing,
+    Added,
+    Transient,
+    Collision
+}
+
+// A single change in a flat, machine-readable shape
+#[derive(Serialize)]
+pub struct Change<'a> {
+    #[serde(rename = "type")]
+    pub change_type: ChangeType,
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hash: &'a str,
+    pub size: Option<u64>
+}
+
+impl Change<'_> {
+    // Where the file is, or was last seen
+    pub fn path(&self) -> &str {
+        self.new_path.as_deref().or(self.old_path.as_deref()).unwrap_or_default()
+    }
+}
+
+impl Comparison {
+    pub fn is_empty(&self) -> bool {
+        self.renamed.is_empty() && self.moved.is_empty() && self.missing.is_empty() && self.added.is_empty()
+            && self.transient.is_empty() && self.collisions.is_empty()
+    }
+
+    pub fn changes(&self) -> Vec<Change<'_>> {
+        let mut changes = Vec::new();
+
+        for doc in &self.renamed {
+            changes.push(Change {
+                change_type: ChangeType::Renamed,
+                old_path: Some(join_encoded(&doc.doc.path, &doc.prior_name)),
+                new_path: Some(doc.doc.full_path()),
+                hash: &doc.doc.hash,
+                size: doc.doc.size
+            });
+        }
+
+        for doc in &self.moved {
+            changes.push(Change {
+                change_type: ChangeType::Moved,
+                old_path: Some(join_encoded(&doc.prior_path, &doc.prior_name)),
+                new_path: Some(doc.doc.full_path()),
+                hash: &doc.doc.hash,
+                size: doc.doc.size
+            });
+        }
+
+        for doc in &self.missing {
+            changes.push(Change {
+                change_type: ChangeType::Missing,
+                old_path: Some(doc.full_path()),
+                new_path: None,
+                hash: &doc.hash,
+                size: doc.size
+            });
+        }
+
+        for doc in &self.added {
+            changes.push(Change {
+                change_type: ChangeType::Added,
+                old_path: None,
+                new_path: Some(doc.full_path()),
+                hash: &doc.hash,
+                size: doc.size
+            });
+        }
+
+        for doc in &self.transient {
+            changes.push(Change {
+                change_type: ChangeType::Transient,
+                old_path: Some(doc.full_path()),
+                new_path: None,
+                hash: &doc.hash,
+                size: doc.size
+            });
+        }
+
+        for doc in self.collisions.iter().flatten() {
+            changes.push(Change {
+                change_type: ChangeType::Collision,
+                old_path: None,
+                new_path: Some(doc.full_path()),
+                hash: &doc.hash,
+                size: doc.size
+            });
+        }
+
+        changes
+    }
+}
+
+// `roots` are the compared directories, used to show paths relative to them
+pub fn print_comparison(out: &mut dyn Write, comparison: &Comparison, format: &OutputFormat,
+                        roots: &[&Path], colored: bool) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Text => print_text(out, comparison)?,
+        OutputFormat::Tree => print_tree(out, comparison, roots, colored)?,
+        OutputFormat::Html => print_html(out, comparison, roots)?,
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&comparison.changes())?)?,
+        OutputFormat::Ndjson => {
+            for change in comparison.changes() {
+                writeln!(out, "{}", serde_json::to_string(&change)?)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_text(out: &mut dyn Write, comparison: &Comparison) -> io::Result<()> {
+    if !comparison.renamed.is_empty() {
+        writeln!(out, "Renamed files:")?;
+        print_renamed_docs(out, &comparison.renamed)?;
+    } else {
+        writeln!(out, "No renamed files")?;
+    }
+
+    if !comparison.moved.is_empty() {
+        writeln!(out, "Moved files:")?;
+        print_moved_docs(out, &comparison.moved)?;
+    } else {
+        writeln!(out, "No moved files")?;
+    }
+
+    if !comparison.missing.is_empty() {
+        writeln!(out, "Missing files:")?;
+        print_docs(out, &comparison.missing)?;
+    } else {
+        writeln!(out, "No missing files")?;
+    }
+
+    if !comparison.added.is_empty() {
+        writeln!(out, "Added files:")?;
+        print_docs(out, &comparison.added)?;
+    } else {
+        writeln!(out, "No added files")?;
+    }
+
+    if !comparison.transient.is_empty() {
+        writeln!(out, "Transient files:")?;
+        print_docs(out, &comparison.transient)?;
+    }
+
+    if !comparison.collisions.is_empty() {
+        writeln!(out, "Name collisions, treated as the same file:")?;
+        for collision in &comparison.collisions {
+            let paths = collision.iter().map(|d| d.full_path()).collect::<Vec<String>>();
+            writeln!(out, "{}", paths.join(", "))?;
+        }
+    }
+
+    Ok(())
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+
+fn paint(text: &str, color: &str, colored: bool) -> String {
+    if colored {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+// Strip whichever compared root the path lives under, leaving it as is otherwise
+pub fn relative_path(path: &str, roots: &[&Path]) -> String {
+    let os_path = decode_os_str(path);
+
+    roots.iter()
+        .filter_map(|root| Path::new(&os_path).strip_prefix(root).ok())
+        .map(|p| encode_os_str(p.as_os_str()))
+        .next()
+        .unwrap_or_else(|| path.to_string())
+}
+
+// Split into directory and name, with files directly under the root living in "."
+pub fn split_path(path: &str) -> (String, String) {
+    match path.rfind('/') {
+        Some(i) if i > 0 => (path[..i].to_string(), path[i + 1..].to_string()),
+        _ => (String::from("."), path.trim_start_matches('/').to_string())
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn size_label(size: Option<u64>) -> String {
+    size.map(|s| format!(" ({})", human_bytes(s))).unwrap_or_default()
+}
+
+// One line per change, grouped by the directory it is in relative to the compared roots
+fn tree_lines(comparison: &Comparison, roots: &[&Path], colored: bool) -> BTreeMap<String, Vec<String>> {
+    let mut dirs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for change in comparison.changes() {
+        let old_path = change.old_path.as_ref().map(|p| relative_path(p, roots)).unwrap_or_default();
+        let (dir, name) = split_path(&relative_path(change.path(), roots));
+        let size = size_label(change.size);
+
+        let line = match change.change_type {
+            ChangeType::Added => format!("{} {}{}", paint("+", GREEN, colored), name, size),
+            ChangeType::Missing => format!("{} {}{}", paint("-", RED, colored), name, size),
+            ChangeType::Renamed => {
+                let (_, old_name) = split_path(&old_path);
+                format!("{} {} -> {}{}", paint("~", YELLOW, colored), old_name, name, size)
+            }
+            ChangeType::Moved => {
+                let (old_dir, _) = split_path(&old_path);
+                format!("{} {} (from {}/){}", paint("\u{2192}", CYAN, colored), name, old_dir, size)
+            }
+            ChangeType::Transient => format!("{} {}{}", paint("\u{b1}", MAGENTA, colored), name, size),
+            ChangeType::Collision => format!("{} {} (name collision){}", paint("!", RED, colored), name, size),
+        };
+
+        dirs.entry(dir).or_default().push(line);
+    }
+
+    dirs
+}
+
+fn print_tree(out: &mut dyn Write, comparison: &Comparison, roots: &[&Path], colored: bool)
+              -> io::Result<()> {
+    for (dir, lines) in &tree_lines(comparison, roots, colored) {
+        writeln!(out, "{}", paint(&format!("{}/", dir), BOLD, colored))?;
+        for line in lines {
+            writeln!(out, "  {}", line)?;
+        }
+    }
+
+    writeln!(out, "{}", summary_line(comparison))
+}
+
+pub fn total_size(docs: &mut dyn Iterator<Item = &Doc>) -> u64 {
+    docs.filter_map(|d| d.size).sum()
+}
+
+pub fn summary_line(comparison: &Comparison) -> String {
+    let summary = format!("{} added ({}), {} missing ({}), {} renamed ({}), {} moved ({})",
+            comparison.added.len(),
+            human_bytes(total_size(&mut comparison.added.iter())),
+            comparison.missing.len(),
+            human_bytes(total_size(&mut comparison.missing.iter())),
+            comparison.renamed.len(),
+            human_bytes(total_size(&mut comparison.renamed.iter().map(|r| &r.doc))),
+            comparison.moved.len(),
+            human_bytes(total_size(&mut comparison.moved.iter().map(|m| &m.doc))));
+
+    let summary = if comparison.transient.is_empty() {
+        summary
+    } else {
+        format!("{}, {} transient ({})", summary, comparison.transient.len(),
+                human_bytes(total_size(&mut comparison.transient.iter())))
+    };
+
+    if comparison.collisions.is_empty() {
+        summary
+    } else {
+        format!("{}, {} name collisions", summary, comparison.collisions.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testutil::doc;
+
+    #[test]
+    fn changes_serialize_with_type_paths_hash_and_size() {
+        let comparison = Comparison {
+            renamed: vec![RenamedDoc { doc: doc("/a", "new.txt", "h1", 3, 2000), prior_name: "old.txt".to_string() }],
+            moved: vec![MovedDoc { doc: doc("/a/sub", "m.txt", "h2", 5, 2000), prior_path: "/a".to_string(),
+                                 prior_name: "m.txt".to_string() }],
+            missing: vec![doc("/a", "gone.txt", "h3", 7, 1000)],
+            added: vec![doc("/a", "born.txt", "h4", 11, 2000)],
+            transient: Vec::new(),
+            collisions: Vec::new()
+        };
+
+        let json = serde_json::to_value(comparison.changes()).unwrap();
+
+        assert_eq!(json, json!([
+            { "type": "renamed", "old_path": "/a/old.txt", "new_path": "/a/new.txt", "hash": "h1", "size": 3 },
+            { "type": "moved", "old_path": "/a/m.txt", "new_path": "/a/sub/m.txt", "hash": "h2", "size": 5 },
+            { "type": "missing", "old_path": "/a/gone.txt", "new_path": null, "hash": "h3", "size": 7 },
+            { "type": "added", "old_path": null, "new_path": "/a/born.txt", "hash": "h4", "size": 11 }
+        ]));
+    }
+
+    fn sample_comparison() -> Comparison {
+        Comparison {
+            renamed: vec![RenamedDoc { doc: doc("/a/docs", "new.txt", "h1", 100, 2000), prior_name: "old.txt".to_string() }],
+            moved: vec![MovedDoc { doc: doc("/a/docs/sub", "m.txt", "h2", 2048, 2000), prior_path: "/a".to_string(),
+                                 prior_name: "m.txt".to_string() }],
+            missing: vec![doc("/a", "gone.txt", "h3", 1, 1000), doc("/a/docs", "lost.txt", "h4", 2, 1000)],
+            added: vec![doc("/a", "born.txt", "h5", 3 * 1024 * 1024, 2000)],
+            transient: Vec::new(),
+            collisions: Vec::new()
+        }
+    }
+
+    #[test]
+    fn tree_groups_changes_by_relative_directory() {
+        let lines = tree_lines(&sample_comparison(), &[Path::new("/a")], false);
+
+        assert_eq!(lines.into_iter().collect::<Vec<_>>(), vec![
+            (".".to_string(), vec!["- gone.txt (1 B)".to_string(), "+ born.txt (3.0 MiB)".to_string()]),
+            ("docs".to_string(), vec!["~ old.txt -> new.txt (100 B)".to_string(), "- lost.txt (2 B)".to_string()]),
+            ("docs/sub".to_string(), vec!["\u{2192} m.txt (from ./) (2.0 KiB)".to_string()])
+        ]);
+    }
+
+    #[test]
+    fn summary_counts_and_sizes_each_change_type() {
+        assert_eq!(summary_line(&sample_comparison()),
+                   "1 added (3.0 MiB), 2 missing (3 B), 1 renamed (100 B), 1 moved (2.0 KiB)");
+    }
+}
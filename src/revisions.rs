@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local};
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic::{commit_temp_file, lock_manifest};
+use crate::dir_csv::millis;
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::manifest::load_entries;
+use crate::report::{human_bytes, total_size};
+
+// Messages and tags of revisions, kept next to the manifest so that tagging a revision does not
+// mean rewriting every entry
+pub const REVISIONS_NAME: &str = ".dirdiff.revisions.csv";
+
+// Selector for the most recent revision
+const LATEST: &str = "latest";
+
+#[derive(Serialize, Deserialize, Default)]
+struct RevisionRow {
+    revision: u64,
+    message: String,
+    // Space separated
+    tags: String
+}
+
+#[derive(Default)]
+pub struct RevisionInfo {
+    pub message: Option<String>,
+    pub tags: Vec<String>
+}
+
+// Revision info by revision date in millis
+pub struct Revisions {
+    path: PathBuf,
+    pub info: BTreeMap<u64, RevisionInfo>
+}
+
+pub fn load_revisions(dir: &Path) -> Result<Revisions> {
+    let path = dir.join(REVISIONS_NAME);
+    let mut info = BTreeMap::new();
+
+    let mut reader = match ReaderBuilder::new().from_path(&path) {
+        Ok(reader) => reader,
+        Err(error) => match error.kind() {
+            csv::ErrorKind::Io(io_error) if io_error.kind() == io::ErrorKind::NotFound =>
+                return Ok(Revisions { path, info }),
+            _ => return Err(DirdiffError::csv(&path, error))
+        }
+    };
+
+    for row in reader.deserialize() {
+        let row: RevisionRow = row.map_err(|e| DirdiffError::csv(&path, e))?;
+        info.insert(row.revision, RevisionInfo {
+            message: Some(row.message).filter(|m| !m.is_empty()),
+            tags: row.tags.split_whitespace().map(String::from).collect()
+        });
+    }
+
+    Ok(Revisions { path, info })
+}
+
+impl Revisions {
+    // Replace the revisions file. The caller must hold the manifest lock.
+    pub fn save(&self) -> Result<()> {
+        let temp_path = self.path.with_file_name(format!("{}.tmp", REVISIONS_NAME));
+
+        let file = File::create(&temp_path).map_err(|e| DirdiffError::io(&temp_path, e))?;
+        let mut writer = WriterBuilder::new().from_writer(&file);
+        for (revision, info) in self.info.iter().filter(|(_, i)| i.message.is_some() || !i.tags.is_empty()) {
+            writer.serialize(RevisionRow {
+                revision: *revision,
+                message: info.message.clone().unwrap_or_default(),
+                tags: info.tags.join(" ")
+            }).map_err(|e| DirdiffError::csv(&temp_path, e))?;
+        }
+        writer.flush().map_err(|e| DirdiffError::io(&temp_path, e))?;
+        drop(writer);
+
+        commit_temp_file(file, &temp_path, &self.path)
+    }
+
+    pub fn tags(&self, revision: SystemTime) -> &[String] {
+        self.info.get(&millis(revision)).map(|i| &i.tags[..]).unwrap_or_default()
+    }
+
+    pub fn message(&self, revision: SystemTime) -> Option<&str> {
+        self.info.get(&millis(revision)).and_then(|i| i.message.as_deref())
+    }
+
+    pub fn is_tagged(&self, revision: SystemTime) -> bool {
+        !self.tags(revision).is_empty()
+    }
+
+    fn tagged(&self, tag: &str) -> Option<u64> {
+        self.info.iter().find(|(_, i)| i.tags.iter().any(|t| t == tag)).map(|(r, _)| *r)
+    }
+
+    pub fn set_message(&mut self, revision: SystemTime, message: &str) {
+        self.info.entry(millis(revision)).or_default().message = Some(message.to_string());
+    }
+
+    pub fn add_tag(&mut self, revision: SystemTime, tag: &str) -> Result<()> {
+        if tag.is_empty() || tag == LATEST || tag.contains(char::is_whitespace) {
+            return Err(DirdiffError::InvalidTag(tag.to_string()));
+        }
+
+        if let Some(tagged) = self.tagged(tag) {
+            return Err(DirdiffError::TagTaken { tag: tag.to_string(), revision: format_revision(from_millis(tagged)) });
+        }
+
+        self.info.entry(millis(revision)).or_default().tags.push(tag.to_string());
+        Ok(())
+    }
+
+    // Drop the info of revisions that are no longer in the manifest
+    pub fn retain(&mut self, revisions: &BTreeSet<SystemTime>) {
+        self.info.retain(|revision, _| revisions.contains(&from_millis(*revision)));
+    }
+
+    // Find a revision by tag, `latest`, its date in millis, or the start of its local date and
+    // time as shown by `log`, e.g. `2020-07-14` for the last revision of that day
+    pub fn resolve(&self, selector: &str, revisions: &BTreeSet<SystemTime>) -> Result<SystemTime> {
+        let not_found = || DirdiffError::UnknownRevision(selector.to_string());
+
+        if let Some(tagged) = self.tagged(selector) {
+            return revisions.get(&from_millis(tagged)).cloned().ok_or_else(not_found);
+        }
+
+        if selector == LATEST {
+            return revisions.iter().next_back().cloned().ok_or_else(not_found);
+        }
+
+        if let Ok(revision) = selector.parse::<u64>() {
+            if let Some(revision) = revisions.get(&from_millis(revision)) {
+                return Ok(*revision);
+            }
+        }
+
+        let prefix = selector.replacen('T', " ", 1);
+        revisions.iter()
+            .rev()
+            .find(|r| format_revision(**r).starts_with(&prefix))
+            .cloned()
+            .ok_or_else(not_found)
+    }
+}
+
+// The entries of the one revision `selector` picks among those in the manifest in `dir`
+pub fn select_revision(dir: &Path, entries: Vec<Doc>, selector: &str) -> Result<(SystemTime, Vec<Doc>)> {
+    let revision_dates = entries.iter().map(|e| e.mod_date).collect::<BTreeSet<SystemTime>>();
+    let revision = load_revisions(dir)?.resolve(selector, &revision_dates)?;
+
+    Ok((revision, entries.into_iter().filter(|e| e.mod_date == revision).collect()))
+}
+
+pub fn from_millis(revision: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(revision)
+}
+
+pub fn format_revision(revision: SystemTime) -> String {
+    DateTime::<Local>::from(revision).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// A revision date along with its tags, for listings
+pub fn describe_revision(revision: SystemTime, revisions: &Revisions) -> String {
+    let tags = revisions.tags(revision);
+
+    if tags.is_empty() {
+        format_revision(revision)
+    } else {
+        format!("{} [{}]", format_revision(revision), tags.join(", "))
+    }
+}
+
+// The `log` command: every revision, newest first, with its size, tags and message
+pub fn print_revisions(out: &mut dyn Write, entries: &[Doc], revisions: &Revisions) -> io::Result<()> {
+    let mut by_revision: BTreeMap<SystemTime, Vec<&Doc>> = BTreeMap::new();
+    for entry in entries {
+        by_revision.entry(entry.mod_date).or_default().push(entry);
+    }
+
+    for (revision, docs) in by_revision.iter().rev() {
+        write!(out, "{}  {} files  {}", describe_revision(*revision, revisions), docs.len(),
+               human_bytes(total_size(&mut docs.iter().cloned())))?;
+
+        match revisions.message(*revision) {
+            Some(message) => writeln!(out, "  {}", message)?,
+            None => writeln!(out)?
+        }
+    }
+
+    Ok(())
+}
+
+// The `tag` command: tag the revision `selector` picks among those in the manifest in `dir`
+pub fn tag_revision(dir: &Path, selector: &str, tag: &str, verbose: bool) -> Result<SystemTime> {
+    let _lock = lock_manifest(dir, verbose)?;
+    let entries = load_entries(dir, verbose, false)?;
+    let revision_dates = entries.iter().map(|e| e.mod_date).collect::<BTreeSet<SystemTime>>();
+
+    let mut revisions = load_revisions(dir)?;
+    let revision = revisions.resolve(selector, &revision_dates)?;
+    revisions.add_tag(revision, tag)?;
+    revisions.save()?;
+
+    Ok(revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    fn revisions() -> (Revisions, BTreeSet<SystemTime>) {
+        let mut revisions = Revisions { path: PathBuf::from(REVISIONS_NAME), info: BTreeMap::new() };
+        revisions.add_tag(from_millis(10 * DAY), "before-reorg").unwrap();
+
+        let dates = [10 * DAY, 20 * DAY, 20 * DAY + 1000, 30 * DAY].iter().map(|d| from_millis(*d)).collect();
+        (revisions, dates)
+    }
+
+    #[test]
+    fn revisions_resolve_from_tags_and_millis() {
+        let (revisions, dates) = revisions();
+
+        assert_eq!(revisions.resolve("before-reorg", &dates).unwrap(), from_millis(10 * DAY));
+        assert_eq!(revisions.resolve("latest", &dates).unwrap(), from_millis(30 * DAY));
+        assert_eq!(revisions.resolve(&(20 * DAY).to_string(), &dates).unwrap(), from_millis(20 * DAY));
+
+        assert!(matches!(revisions.resolve("after-reorg", &dates), Err(DirdiffError::UnknownRevision(_))));
+        assert!(matches!(revisions.resolve("latest", &BTreeSet::new()), Err(DirdiffError::UnknownRevision(_))));
+    }
+
+    #[test]
+    fn date_prefixes_pick_the_last_matching_revision() {
+        let (revisions, dates) = revisions();
+        let second = format_revision(from_millis(20 * DAY + 1000));
+
+        // The same day, whatever the local time zone makes of it
+        assert_eq!(revisions.resolve(&second[..10], &dates).unwrap(), from_millis(20 * DAY + 1000));
+        assert_eq!(revisions.resolve(&format_revision(from_millis(20 * DAY)), &dates).unwrap(), from_millis(20 * DAY));
+        assert_eq!(revisions.resolve(&second.replacen(' ', "T", 1), &dates).unwrap(), from_millis(20 * DAY + 1000));
+        assert!(revisions.resolve("1969-01-01", &dates).is_err());
+    }
+
+    #[test]
+    fn tags_are_unique_and_cannot_be_selectors() {
+        let (mut revisions, _) = revisions();
+
+        assert!(matches!(revisions.add_tag(from_millis(20 * DAY), "before-reorg"), Err(DirdiffError::TagTaken { .. })));
+        for tag in &["", "latest", "two words"] {
+            assert!(matches!(revisions.add_tag(from_millis(20 * DAY), tag), Err(DirdiffError::InvalidTag(_))));
+        }
+    }
+}
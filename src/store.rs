@@ -0,0 +1,115 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+
+use crate::error::{DirdiffError, Result};
+use crate::names::encode_os_str;
+
+// Inside a central store, each tracked root gets a directory under `roots` named by its root ID,
+// holding its manifests along with a `root` file naming the tracked directory
+const ROOTS_DIR: &str = "roots";
+const ROOT_FILE: &str = "root";
+
+// Where the manifests of tracked directories are kept
+#[derive(Clone)]
+pub enum Store {
+    // In the tracked directory itself
+    InTree,
+    // In a state directory of their own, so the tracked directory is never written to
+    Central(PathBuf)
+}
+
+// $XDG_DATA_HOME/dirdiff, defaulting to ~/.local/share/dirdiff
+pub fn data_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+        .map(|dir| dir.join("dirdiff"))
+}
+
+fn canonical_root(root: &Path) -> Result<PathBuf> {
+    let canonical = root.canonicalize().map_err(|e| DirdiffError::io(root, e))?;
+
+    if !canonical.is_dir() {
+        return Err(DirdiffError::NotADirectory(root.to_path_buf()));
+    }
+
+    Ok(canonical)
+}
+
+// Stable identifier of a tracked directory: the start of the SHA-1 of its canonical path, so
+// the same directory maps to the same catalog however it is named on the command line
+pub fn root_id(root: &Path) -> Result<String> {
+    let canonical = canonical_root(root)?;
+    let digest = Sha1::digest(encode_os_str(canonical.as_os_str()).as_bytes());
+
+    Ok(hex::encode(&digest[..8]))
+}
+
+impl Store {
+    // The directory holding the manifests of `root`, which may not exist yet
+    pub fn manifest_dir(&self, root: &Path) -> Result<PathBuf> {
+        match self {
+            Store::InTree => Ok(root.to_path_buf()),
+            Store::Central(store) => Ok(store.join(ROOTS_DIR).join(root_id(root)?))
+        }
+    }
+
+    // Same as `manifest_dir`, creating the directory so manifests can be written to it
+    pub fn create_manifest_dir(&self, root: &Path) -> Result<PathBuf> {
+        let dir = self.manifest_dir(root)?;
+
+        if let Store::Central(_) = self {
+            fs::create_dir_all(&dir).map_err(|e| DirdiffError::io(&dir, e))?;
+
+            let root_file = dir.join(ROOT_FILE);
+            if !root_file.exists() {
+                let canonical = canonical_root(root)?;
+                fs::write(&root_file, encode_os_str(canonical.as_os_str()))
+                    .map_err(|e| DirdiffError::io(&root_file, e))?;
+            }
+        }
+
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    #[test]
+    fn in_tree_manifests_live_in_the_tracked_directory() {
+        let dir = ScratchDir::new("store-in-tree");
+
+        assert_eq!(Store::InTree.manifest_dir(&dir).unwrap(), dir.to_path_buf());
+        assert_eq!(Store::InTree.create_manifest_dir(&dir).unwrap(), dir.to_path_buf());
+        assert!(fs::read_dir(&dir).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn central_manifest_dirs_are_named_by_root_id() {
+        let dir = ScratchDir::new("store-central");
+        let (root, state) = (dir.join("root"), dir.join("state"));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let store = Store::Central(state.clone());
+
+        let manifest_dir = store.manifest_dir(&root).unwrap();
+        assert_eq!(manifest_dir, state.join(ROOTS_DIR).join(root_id(&root).unwrap()));
+        assert_eq!(store.manifest_dir(&root.join("sub").join("..")).unwrap(), manifest_dir);
+        assert_ne!(store.manifest_dir(&root.join("sub")).unwrap(), manifest_dir);
+        assert!(!manifest_dir.exists());
+
+        assert!(store.manifest_dir(&dir.join("missing")).is_err());
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(matches!(store.manifest_dir(&dir.join("file")), Err(DirdiffError::NotADirectory(_))));
+
+        assert_eq!(store.create_manifest_dir(&root).unwrap(), manifest_dir);
+        let recorded = fs::read_to_string(manifest_dir.join(ROOT_FILE)).unwrap();
+        assert_eq!(Path::new(&recorded), root.canonicalize().unwrap());
+    }
+}
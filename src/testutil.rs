@@ -0,0 +1,53 @@
+// Fixtures shared by the unit tests
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::docs::Doc;
+
+// An entry of the revision taken `date` milliseconds after the epoch
+pub fn doc(path: &str, name: &str, hash: &str, size: u64, date: u64) -> Doc {
+    Doc {
+        hash: hash.to_string(),
+        name: name.to_string(),
+        path: path.to_string(),
+        mod_date: UNIX_EPOCH + Duration::from_millis(date),
+        size: Some(size),
+        error: None,
+        mtime: None
+    }
+}
+
+// An empty directory for a single test, removed along with its contents when dropped. The
+// name keeps tests running in parallel apart.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("dirdiff-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dir_csv::{hash_file, millis};
+use crate::docs::Doc;
+use crate::error::{DirdiffError, Result};
+use crate::names::decode_os_str;
+
+// How many of the recorded files to re-hash
+#[derive(Clone, Copy)]
+pub enum Sample {
+    All,
+    Percent(f64),
+    Count(usize)
+}
+
+impl FromStr for Sample {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid sample {}, expected a percentage up to 100% like 5%, or a file count", s);
+
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse::<f64>() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(Sample::Percent(percent)),
+                _ => Err(invalid())
+            },
+            None => match s.parse::<usize>() {
+                Ok(count) if count > 0 => Ok(Sample::Count(count)),
+                _ => Err(invalid())
+            }
+        }
+    }
+}
+
+impl Sample {
+    fn size(&self, total: usize) -> usize {
+        match self {
+            Sample::All => total,
+            // At least one file, so small samples of small trees still check something
+            Sample::Percent(percent) => ((total as f64 * percent / 100.0).ceil() as usize).min(total),
+            Sample::Count(count) => (*count).min(total)
+        }
+    }
+}
+
+// xorshift64*, good enough to pick which files to check without pulling in a crate
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn new() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        SampleRng(seed | 1)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound as u64) as usize
+    }
+}
+
+// A random subset of `entries`, of the size `sample` asks for
+fn pick_sample(mut entries: Vec<Doc>, sample: Sample) -> Vec<Doc> {
+    let size = sample.size(entries.len());
+    let mut rng = SampleRng::new();
+
+    // Partial Fisher-Yates shuffle
+    for i in 0..size {
+        let j = i + rng.below(entries.len() - i);
+        entries.swap(i, j);
+    }
+
+    entries.truncate(size);
+    entries
+}
+
+// Same size and modification time as recorded, yet different contents: nothing but the disk
+// should have changed the file
+pub struct Corruption {
+    pub path: PathBuf,
+    pub recorded_hash: String,
+    pub current_hash: String
+}
+
+#[derive(Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub corrupted: Vec<Corruption>,
+    // Changed size or modification time, most likely edited on purpose
+    pub modified: usize,
+    pub missing: usize,
+    pub unreadable: usize,
+    // Recorded before modification times were, so a changed hash proves nothing
+    pub no_mtime: usize
+}
+
+// Re-hash the files of the latest revision and flag the ones whose contents changed while their
+// size and modification time did not
+pub fn verify_files(entries: Vec<Doc>, sample: Sample, verbose: bool) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let entries = entries.into_iter().filter(|e| !e.is_unreadable()).collect::<Vec<Doc>>();
+
+    for entry in pick_sample(entries, sample) {
+        let path = PathBuf::from(decode_os_str(&entry.full_path()));
+
+        let recorded_mtime = match entry.mtime {
+            Some(mtime) => mtime,
+            None => {
+                report.no_mtime += 1;
+                continue
+            }
+        };
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                report.missing += 1;
+                continue
+            }
+            Err(_) => {
+                report.unreadable += 1;
+                continue
+            }
+        };
+
+        // Manifests keep modification times to the millisecond
+        let unchanged = Some(metadata.len()) == entry.size
+            && metadata.modified().map(millis).ok() == Some(millis(recorded_mtime));
+        if !unchanged {
+            report.modified += 1;
+            continue
+        }
+
+        if verbose { println!("Verifying {}", path.display()); }
+
+        match hash_file(&path) {
+            Ok((hash, _)) => {
+                report.checked += 1;
+                if hash != entry.hash {
+                    report.corrupted.push(Corruption { path, recorded_hash: entry.hash, current_hash: hash });
+                }
+            }
+            Err(_) => report.unreadable += 1
+        }
+    }
+
+    report
+}
+
+pub fn print_verify_report(out: &mut dyn Write, report: &VerifyReport) -> Result<()> {
+    let write_error = |e| DirdiffError::io(Path::new("<stdout>"), e);
+
+    for corruption in &report.corrupted {
+        writeln!(out, "Corrupted: {} (recorded {}, now {})", corruption.path.display(),
+                 corruption.recorded_hash, corruption.current_hash).map_err(write_error)?;
+    }
+
+    writeln!(out, "Verified {} files: {} corrupted, {} modified since recorded, {} missing, {} unreadable",
+             report.checked, report.corrupted.len(), report.modified, report.missing, report.unreadable)
+        .map_err(write_error)?;
+
+    if report.no_mtime > 0 {
+        writeln!(out, "Skipped {} files recorded without a modification time; record again to verify them",
+                 report.no_mtime).map_err(write_error)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    use crate::names::encode_os_str;
+    use crate::testutil::{doc, ScratchDir};
+
+    #[test]
+    fn samples_are_percentages_or_counts() {
+        assert!(matches!("5%".parse::<Sample>(), Ok(Sample::Percent(p)) if p == 5.0));
+        assert!(matches!("100%".parse::<Sample>(), Ok(Sample::Percent(p)) if p == 100.0));
+        assert!(matches!("12".parse::<Sample>(), Ok(Sample::Count(12))));
+
+        for invalid in &["0%", "101%", "-5%", "0", "-1", "some", ""] {
+            assert!(invalid.parse::<Sample>().is_err(), "accepted {}", invalid);
+        }
+    }
+
+    #[test]
+    fn sample_sizes_round_up_and_never_exceed_the_tree() {
+        assert_eq!(Sample::All.size(7), 7);
+        assert_eq!(Sample::Percent(5.0).size(10), 1);
+        assert_eq!(Sample::Percent(50.0).size(7), 4);
+        assert_eq!(Sample::Count(3).size(10), 3);
+        assert_eq!(Sample::Count(30).size(10), 10);
+        assert_eq!(pick_sample(vec![doc("a", "x", "h", 1, 1000); 10], Sample::Count(4)).len(), 4);
+    }
+
+    // An entry recording `name` in `dir` as it is now
+    fn recorded(dir: &Path, name: &str) -> Doc {
+        let path = dir.join(name);
+        let (hash, size) = hash_file(&path).unwrap();
+
+        let mut entry = doc(&encode_os_str(dir.as_os_str()), name, &hash, size, 1000);
+        entry.mtime = Some(fs::metadata(&path).unwrap().modified().unwrap());
+        entry
+    }
+
+    #[test]
+    fn changed_contents_with_the_same_size_and_mtime_are_corruption() {
+        let dir = ScratchDir::new("verify-corruption");
+        for name in &["rotten", "edited", "intact", "deleted"] {
+            fs::write(dir.join(name), "contents").unwrap();
+        }
+        let mut entries = ["rotten", "edited", "intact", "deleted"].iter().map(|n| recorded(&dir, n)).collect::<Vec<_>>();
+        let mut legacy = recorded(&dir, "intact");
+        legacy.mtime = None;
+        entries.push(legacy);
+
+        // Flip the contents behind the file system's back, keeping size and modification time
+        let rotten = dir.join("rotten");
+        let mtime = fs::metadata(&rotten).unwrap().modified().unwrap();
+        fs::write(&rotten, "contentz").unwrap();
+        File::options().write(true).open(&rotten).unwrap().set_modified(mtime).unwrap();
+
+        fs::write(dir.join("edited"), "new contents").unwrap();
+        fs::remove_file(dir.join("deleted")).unwrap();
+
+        let report = verify_files(entries, Sample::All, false);
+
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].path, rotten);
+        assert_eq!(report.checked, 2);
+        assert_eq!((report.modified, report.missing, report.unreadable, report.no_mtime), (1, 1, 0, 1));
+    }
+
+    #[test]
+    fn touched_files_are_modified_rather_than_corrupted() {
+        let dir = ScratchDir::new("verify-touched");
+        fs::write(dir.join("touched"), "contents").unwrap();
+        let entry = recorded(&dir, "touched");
+
+        fs::write(dir.join("touched"), "contentz").unwrap();
+        let later = entry.mtime.unwrap() + Duration::from_secs(5);
+        File::options().write(true).open(dir.join("touched")).unwrap().set_modified(later).unwrap();
+
+        let report = verify_files(vec![entry], Sample::All, false);
+        assert!(report.corrupted.is_empty());
+        assert_eq!((report.checked, report.modified), (0, 1));
+    }
+}